@@ -0,0 +1,314 @@
+//! The `aws-ec2.toml` configuration file and merging it with the command line arguments.
+
+use aws_ec2::{Artifact, Cidr, Connect, JumpHost, TransportKind, VolumeSize};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The configuration file used if it exists in the current directory and `--config` isn't given.
+pub const DEFAULT_CONFIG_FILENAME: &str = "aws-ec2.toml";
+
+/// The contents of a configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Named instance types and AMIs to run on.
+    #[serde(default)]
+    pub targets: BTreeMap<String, TargetConfig>,
+    /// Named commands to run.
+    #[serde(default)]
+    pub jobs: BTreeMap<String, JobConfig>,
+}
+
+/// A named target in a configuration file.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetConfig {
+    pub instance: String,
+    pub ami: String,
+    /// The size in GB of the EBS volume.
+    pub size: Option<VolumeSize>,
+    /// The user to login as over SSH.
+    pub user: Option<String>,
+    /// The AWS region to launch the instance in, by default the region from the environment.
+    pub region: Option<String>,
+}
+
+/// A named job in a configuration file.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobConfig {
+    /// Commands run in order before the steps, as steps named `setup-<n>`.
+    #[serde(default)]
+    pub setup: Vec<String>,
+    /// Steps run in order before `command`.
+    #[serde(default)]
+    pub steps: Vec<StepConfig>,
+    pub command: Option<String>,
+    /// Files to download after the command finishes, as `<remote-glob>:<local-dir>`.
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
+    /// Seconds each step may run for.
+    pub timeout: Option<u64>,
+    /// The names of the targets to run on, by default all targets.
+    pub targets: Option<Vec<String>>,
+}
+
+/// A named step of a job.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct StepConfig {
+    pub name: String,
+    /// The command to run.
+    pub run: String,
+    /// Don't fail the job or skip the later steps if this step fails.
+    #[serde(default)]
+    pub continue_on_error: bool,
+    /// Run this step even if an earlier step failed.
+    #[serde(default)]
+    pub always: bool,
+    /// Seconds this step may run for, overriding the timeout of the job.
+    pub timeout: Option<u64>,
+}
+
+impl JobConfig {
+    /// The setup commands, as steps named `setup-<n>`, followed by the steps.
+    pub fn all_steps(&self) -> Vec<StepConfig> {
+        self.setup
+            .iter()
+            .enumerate()
+            .map(|(i, run)| StepConfig {
+                name: format!("setup-{}", i + 1),
+                run: run.clone(),
+                continue_on_error: false,
+                always: false,
+                timeout: None,
+            })
+            .chain(self.steps.iter().cloned())
+            .collect()
+    }
+}
+
+impl ConfigFile {
+    /// Loads the configuration file at `path`, or if `path` is `None` the default configuration
+    /// file if it exists.
+    ///
+    /// Returns the path the configuration was loaded from.
+    pub fn load(path: Option<&Path>) -> Result<Option<(PathBuf, Self)>, String> {
+        let explicit = path.is_some();
+        let path = path.map_or_else(|| PathBuf::from(DEFAULT_CONFIG_FILENAME), Path::to_path_buf);
+        if !path.exists() && !explicit {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        let config = Self::parse(&contents).map_err(|err| format!("{}: {err}", path.display()))?;
+        Ok(Some((path, config)))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    /// The job `name`, or if `name` is `None` the only job.
+    ///
+    /// Returns `None` if there are no jobs and no name is given.
+    pub fn job(&self, name: Option<&str>) -> Result<Option<(&str, &JobConfig)>, String> {
+        match name {
+            Some(name) => self
+                .jobs
+                .get_key_value(name)
+                .map(|(name, job)| Some((name.as_str(), job)))
+                .ok_or_else(|| {
+                    format!(
+                        "job {name:?} not found, expected one of {:?}",
+                        self.job_names()
+                    )
+                }),
+            None if self.jobs.len() > 1 => Err(format!(
+                "multiple jobs are defined, select one of {:?} with `--job`",
+                self.job_names()
+            )),
+            None => Ok(self
+                .jobs
+                .iter()
+                .next()
+                .map(|(name, job)| (name.as_str(), job))),
+        }
+    }
+
+    /// The targets `job` runs on.
+    pub fn targets(&self, job: Option<&JobConfig>) -> Result<Vec<(String, TargetConfig)>, String> {
+        let Some(names) = job.and_then(|job| job.targets.as_ref()) else {
+            return Ok(self
+                .targets
+                .iter()
+                .map(|(name, target)| (name.clone(), target.clone()))
+                .collect());
+        };
+        names
+            .iter()
+            .map(|name| match self.targets.get(name) {
+                Some(target) => Ok((name.clone(), target.clone())),
+                None => Err(format!("target {name:?} not found")),
+            })
+            .collect()
+    }
+
+    fn job_names(&self) -> Vec<&str> {
+        self.jobs.keys().map(String::as_str).collect()
+    }
+}
+
+/// The effective configuration of a run, after merging the configuration file and the command
+/// line arguments.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Settings {
+    /// The configuration file loaded.
+    pub config: Option<PathBuf>,
+    /// The job from the configuration file.
+    pub job: Option<String>,
+    /// Run after the steps.
+    pub command: Option<String>,
+    pub launch_timeout: u64,
+    pub ssh_timeout: u64,
+    pub transfer_timeout: u64,
+    pub command_timeout: u64,
+    pub deadline: Option<u64>,
+    pub artifacts: Vec<Artifact>,
+    pub path: Option<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub remote_dir: Option<String>,
+    pub key_name: Option<String>,
+    pub security_group_name: Option<String>,
+    pub security_group_id: Option<String>,
+    pub ssh_cidr: Vec<Cidr>,
+    pub linger: Option<u64>,
+    pub linger_on_failure: bool,
+    pub insecure_skip_host_key_check: bool,
+    pub connect: Connect,
+    pub jump_host: Option<JumpHost>,
+    pub jump_identity: Option<PathBuf>,
+    pub transport: TransportKind,
+    pub instance_profile: Option<String>,
+    pub ssm_bucket: Option<String>,
+    /// Kept last with `spot`, `steps` and `targets` as tables must follow values in TOML.
+    pub network: NetworkSettings,
+    pub spot: Option<SpotSettings>,
+    pub steps: Vec<StepConfig>,
+    pub targets: Vec<TargetSettings>,
+}
+
+/// Where instances are launched in the effective configuration.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NetworkSettings {
+    pub vpc_id: Option<String>,
+    pub subnet_id: Option<String>,
+    pub availability_zone: Option<String>,
+    pub placement_group: Option<String>,
+    /// Kept last as tables must follow values in TOML.
+    pub subnet_tags: BTreeMap<String, String>,
+}
+
+/// Spot instance options of the effective configuration.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SpotSettings {
+    pub max_price: Option<String>,
+    pub other_zones: bool,
+    pub fallback_on_demand: bool,
+}
+
+/// A target of the effective configuration.
+#[derive(Debug, Serialize)]
+pub struct TargetSettings {
+    /// The name of the target in the configuration file.
+    pub name: Option<String>,
+    pub instance: String,
+    pub ami: String,
+    pub size: VolumeSize,
+    pub user: Option<String>,
+    pub region: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [targets.ubuntu]
+        instance = "t2.medium"
+        ami = "ami-0eb260c4d5475b901"
+        size = 32
+
+        [targets.graviton]
+        instance = "t4g.medium"
+        ami = "ami-0e3f80b3d2a794117"
+        user = "ubuntu"
+        region = "eu-west-1"
+
+        [jobs.test]
+        setup = ["sudo apt-get -y update"]
+        command = "cargo test"
+        artifacts = ["target/*.xml:reports"]
+        timeout = 600
+        targets = ["graviton"]
+
+        [jobs.bench]
+        command = "cargo bench"
+
+        [[jobs.bench.steps]]
+        name = "build"
+        run = "cargo build --release"
+
+        [[jobs.bench.steps]]
+        name = "clean"
+        run = "cargo clean"
+        timeout = 60
+        always = true
+    "#;
+
+    #[test]
+    fn config_file_jobs_and_targets() {
+        let config = ConfigFile::parse(CONFIG).unwrap();
+
+        let (name, job) = config.job(Some("test")).unwrap().unwrap();
+        assert_eq!(name, "test");
+        assert_eq!(job.setup, ["sudo apt-get -y update"]);
+        assert_eq!(job.timeout, Some(600));
+        assert_eq!(job.artifacts, [Artifact::new("target/*.xml", "reports")]);
+        let targets = config.targets(Some(job)).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].0, "graviton");
+        assert_eq!(targets[0].1.region.as_deref(), Some("eu-west-1"));
+
+        // Jobs without targets run on all of them.
+        let (_, job) = config.job(Some("bench")).unwrap().unwrap();
+        assert_eq!(job.steps.len(), 2);
+        assert!(!job.steps[0].always && job.steps[1].always);
+        assert_eq!(job.steps[1].timeout, Some(60));
+        let names = config
+            .targets(Some(job))
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["graviton", "ubuntu"]);
+
+        assert!(config.job(None).is_err());
+        assert!(config.job(Some("missing")).is_err());
+    }
+
+    #[test]
+    fn config_file_rejects_unknown_fields() {
+        let err =
+            ConfigFile::parse("[targets.a]\ninstance = \"t2.medium\"\nami = \"a\"\nsize_gb = 1\n")
+                .unwrap_err();
+        assert!(err.to_string().contains("size_gb"));
+        assert!(ConfigFile::parse("[jobs.a]\nartifacts = [\"reports\"]\n").is_err());
+        assert!(ConfigFile::parse("").unwrap().job(None).unwrap().is_none());
+    }
+}
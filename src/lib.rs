@@ -0,0 +1,5937 @@
+#![warn(clippy::pedantic)]
+#![allow(clippy::type_complexity)]
+#![allow(clippy::result_large_err)]
+
+//! Runs commands on AWS EC2 instances.
+//!
+//! A [`RunConfig`] describes the instances to launch and what to run on them, a [`Runner`] then
+//! either does the whole run with [`Runner::run`] or each step with [`Runner::launch`],
+//! [`Runner::upload`], [`Runner::exec`], [`Runner::download`] and [`Runner::terminate`].
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), aws_ec2::MainError> {
+//! use aws_ec2::{RunConfig, Runner, Target};
+//! use aws_sdk_ec2::types::InstanceType;
+//!
+//! let config = aws_config::load_from_env().await;
+//! let client = aws_sdk_ec2::Client::new(&config);
+//! let runner = Runner::new(
+//!     client,
+//!     RunConfig::new()
+//!         .target(Target::new(InstanceType::T2Medium, "ami-0eb260c4d5475b901"))
+//!         .command("uname -a"),
+//! );
+//! let target = Target::new(InstanceType::T2Medium, "ami-0eb260c4d5475b901");
+//! let mut instance = runner.launch(&target).await?;
+//! runner.upload(&mut instance).await?;
+//! let code = runner.exec(&instance, "uname -a").await?;
+//! runner.terminate(instance).await?;
+//! runner.cleanup().await;
+//! # Ok(())
+//! # }
+//! ```
+
+// SSH sessions are driven through unix sockets (`AsyncFd`, socket pairs for jump hosts), and
+// commands are run and killed with POSIX shell scripts.
+#[cfg(not(unix))]
+compile_error!("aws-ec2 only supports unix platforms");
+
+pub mod ssm;
+
+use aws_sdk_ec2 as ec2;
+use ec2::error::ProvideErrorMetadata;
+use ec2::types::InstanceType;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::ErrorKind;
+
+use std::io::ErrorKind::WouldBlock;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use std::time::Duration;
+use std::time::Instant;
+use tracing::{debug, error, info, info_span, Instrument};
+
+/// The AWS account which owns official Ubuntu AMIs.
+const CANONICAL_OWNER_ID: &str = "099720109477";
+/// The AWS account which owns official Debian AMIs.
+const DEBIAN_OWNER_ID: &str = "136693071363";
+/// The AWS account which owns official RHEL AMIs.
+const RED_HAT_OWNER_ID: &str = "309956199498";
+/// The AWS account which owns official Fedora AMIs.
+const FEDORA_OWNER_ID: &str = "125523088429";
+
+/// `libssh2` error returned when the server rejects authentication.
+const LIBSSH2_ERROR_AUTHENTICATION_FAILED: i32 = -18;
+/// `libssh2` error returned when the server rejects the public key.
+const LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED: i32 = -19;
+
+/// Responds to HTTP requests with the public IPv4 address they came from.
+const IPV4_ECHO_HOST: &str = "checkip.amazonaws.com";
+/// Responds to HTTP requests with the public IPv6 address they came from.
+const IPV6_ECHO_HOST: &str = "api6.ipify.org";
+/// The maximum time to spend asking each echo host for the public IP address.
+const PUBLIC_IP_DETECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The name of ignore files specific to this tool, using the same syntax as `.gitignore`.
+const CUSTOM_IGNORE_FILENAME: &str = ".awsec2ignore";
+
+/// The default port used by ec2 for ssh.
+const EC2_SSH_PORT: VolumeSize = 22;
+
+// TODO This should only be default for optional command line argument.
+const INSTANCE_POLL_STATE_SLEEP: Duration = Duration::from_secs(1);
+
+// TODO This should only be default for optional command line argument.
+const SECURITY_GROUP_DESCRIPTION: &str = "test-aws-security-group-description";
+
+/// Default command to run on the host.
+pub const DEFAULT_COMMAND: &str = "cat /proc/cpuinfo && uname -a && ls";
+/// The directory on the instance `--path` is extracted into and the command is run in, relative
+/// to the home directory of the login user.
+const DEFAULT_REMOTE_DIR: &str = "aws-ec2";
+
+/// The initial time to wait between attempts to connect to SSH on a newly started instance.
+const SSH_READY_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The maximum time to wait between attempts to connect to SSH on a newly started instance.
+const SSH_READY_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The maximum time a single attempt to connect to SSH may take.
+const SSH_READY_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The time to wait before retrying an SSH operation which blocked without waiting on the socket.
+const SSH_RETRY_SLEEP: Duration = Duration::from_millis(10);
+
+/// The time to wait between polls of the console output for the SSH host keys.
+///
+/// The console output is only updated periodically so there is no benefit in polling frequently.
+const CONSOLE_OUTPUT_POLL_SLEEP: Duration = Duration::from_secs(5);
+
+/// The time to wait between attempts to delete the security group.
+///
+/// After an instance is terminated it can take some time for its network interface to be released,
+/// until then deleting the security group fails with `DependencyViolation`.
+const DELETE_SECURITY_GROUP_RETRY_SLEEP: Duration = Duration::from_secs(5);
+
+/// The default timeout for launching an instance and waiting for it to be running, also used when
+/// terminating instances and deleting resources.
+pub const DEFAULT_LAUNCH_TIMEOUT_SECS: u64 = 300;
+
+/// The default timeout for connecting SSH, including waiting for the instance to print its host
+/// keys.
+pub const DEFAULT_SSH_TIMEOUT_SECS: u64 = 300;
+
+/// The default timeout for each transfer of the source or artifacts.
+pub const DEFAULT_TRANSFER_TIMEOUT_SECS: u64 = 300;
+
+/// The default timeout for each step run on an instance.
+pub const DEFAULT_COMMAND_TIMEOUT_SECS: u64 = 300;
+
+/// The error codes of `RunInstances` when there is no spot capacity at the max price.
+const SPOT_CAPACITY_ERROR_CODES: &[&str] = &[
+    "InsufficientInstanceCapacity",
+    "SpotMaxPriceTooLow",
+    "MaxSpotInstanceCountExceeded",
+];
+
+/// The prefixes of the spot request status codes set when the instance is about to be, or has
+/// been, interrupted.
+///
+/// See <https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/spot-request-status.html>.
+const SPOT_INTERRUPTION_STATUS_PREFIXES: &[&str] =
+    &["marked-for-", "instance-terminated-", "instance-stopped-"];
+
+/// How often the spot request of a spot instance is checked for interruption.
+const SPOT_INTERRUPTION_POLL_SLEEP: Duration = Duration::from_secs(10);
+
+/// How long a timed out command is given to exit after `SIGTERM`, before it is sent `SIGKILL`.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// The timeout for killing a timed out command.
+const KILL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The exit code returned when a command times out, as `timeout(1)` does.
+pub const TIMEOUT_EXIT_CODE: u8 = 124;
+
+/// The exit code returned when a run fails for a reason other than the command, e.g. launching an
+/// instance, connecting SSH or deleting resources failed.
+pub const INFRASTRUCTURE_EXIT_CODE: u8 = 125;
+
+/// The default size of the EBS volume of each instance, in GiB.
+pub const DEFAULT_SIZE: VolumeSize = 16;
+
+/// The key of the tag identifying resources created by this tool.
+const TAG_CREATED_BY: &str = "created-by";
+/// The value of the tag identifying resources created by this tool.
+const TAG_CREATED_BY_VALUE: &str = "aws-ec2";
+/// The key of the tag holding the id of the run which created a resource.
+const TAG_RUN_ID: &str = "run-id";
+/// The key of the tag holding the time a resource was created.
+const TAG_CREATED_AT: &str = "created-at";
+
+/// The size of an EBS volume in GiB.
+pub type VolumeSize = u16;
+
+/// Aliases for the latest AMIs of common distributions, with the public SSM parameters holding
+/// their ids for `x86_64` and `arm64` instance types.
+const AMI_ALIASES: &[(&str, &str, &str)] = &[
+    (
+        "ubuntu-20.04",
+        "/aws/service/canonical/ubuntu/server/20.04/stable/current/amd64/hvm/ebs-gp2/ami-id",
+        "/aws/service/canonical/ubuntu/server/20.04/stable/current/arm64/hvm/ebs-gp2/ami-id",
+    ),
+    (
+        "ubuntu-22.04",
+        "/aws/service/canonical/ubuntu/server/22.04/stable/current/amd64/hvm/ebs-gp2/ami-id",
+        "/aws/service/canonical/ubuntu/server/22.04/stable/current/arm64/hvm/ebs-gp2/ami-id",
+    ),
+    (
+        "ubuntu-24.04",
+        "/aws/service/canonical/ubuntu/server/24.04/stable/current/amd64/hvm/ebs-gp3/ami-id",
+        "/aws/service/canonical/ubuntu/server/24.04/stable/current/arm64/hvm/ebs-gp3/ami-id",
+    ),
+    (
+        "debian-11",
+        "/aws/service/debian/release/11/latest/amd64",
+        "/aws/service/debian/release/11/latest/arm64",
+    ),
+    (
+        "debian-12",
+        "/aws/service/debian/release/12/latest/amd64",
+        "/aws/service/debian/release/12/latest/arm64",
+    ),
+    (
+        "al2",
+        "/aws/service/ami-amazon-linux-latest/amzn2-ami-hvm-x86_64-gp2",
+        "/aws/service/ami-amazon-linux-latest/amzn2-ami-hvm-arm64-gp2",
+    ),
+    (
+        "al2023",
+        "/aws/service/ami-amazon-linux-latest/al2023-ami-kernel-default-x86_64",
+        "/aws/service/ami-amazon-linux-latest/al2023-ami-kernel-default-arm64",
+    ),
+];
+
+/// The owner `name:` AMIs are looked up in when none is given.
+const DEFAULT_AMI_OWNER: &str = "self";
+
+/// The security group instances are launched in.
+#[derive(Debug, Clone)]
+pub enum SecurityGroup {
+    /// An existing security group with the given id.
+    Existing(String),
+    /// A security group to create with the given name, allowing SSH ingress from `ssh_cidrs`.
+    ///
+    /// If `name` is `None` a name is generated from the run id. If `ssh_cidrs` is empty the public
+    /// IP addresses of this machine are used.
+    Create {
+        name: Option<String>,
+        ssh_cidrs: Vec<Cidr>,
+    },
+}
+
+impl Default for SecurityGroup {
+    fn default() -> Self {
+        Self::Create {
+            name: None,
+            ssh_cidrs: Vec::new(),
+        }
+    }
+}
+
+/// An IPv4 or IPv6 CIDR block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    address: std::net::IpAddr,
+    prefix: u8,
+}
+
+impl From<std::net::IpAddr> for Cidr {
+    /// The CIDR block containing only `address`.
+    fn from(address: std::net::IpAddr) -> Self {
+        let prefix = if address.is_ipv4() { 32 } else { 128 };
+        Self { address, prefix }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = s
+            .split_once('/')
+            .ok_or_else(|| format!("{s:?} is missing a `/<prefix>`"))?;
+        let address = std::net::IpAddr::from_str(address).map_err(|err| err.to_string())?;
+        let prefix = u8::from_str(prefix).map_err(|err| err.to_string())?;
+        if prefix > Self::from(address).prefix {
+            return Err(format!("prefix {prefix} is too long for {address}"));
+        }
+        Ok(Self { address, prefix })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl serde::Serialize for Cidr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Files to download from an instance after the command finishes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    /// A glob expanded by the remote shell.
+    glob: String,
+    /// The local directory the matching files are unpacked into.
+    local_dir: std::path::PathBuf,
+}
+
+impl std::fmt::Display for Artifact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.glob, self.local_dir.display())
+    }
+}
+
+impl Artifact {
+    #[must_use]
+    pub fn new(glob: impl Into<String>, local_dir: impl Into<std::path::PathBuf>) -> Self {
+        Self {
+            glob: glob.into(),
+            local_dir: local_dir.into(),
+        }
+    }
+}
+
+impl FromStr for Artifact {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((glob, local_dir)) if !glob.is_empty() && !local_dir.is_empty() => Ok(Self {
+                glob: String::from(glob),
+                local_dir: std::path::PathBuf::from(local_dir),
+            }),
+            _ => Err(format!(
+                "{s:?} is not of the form `<remote-glob>:<local-dir>`"
+            )),
+        }
+    }
+}
+
+impl serde::Serialize for Artifact {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Artifact {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A local directory to copy to each instance.
+#[derive(Debug, Clone)]
+pub struct Source {
+    path: String,
+    /// Globs of files to include, if empty all files are included.
+    include: Vec<String>,
+    /// Globs of files to exclude.
+    exclude: Vec<String>,
+    /// The directory on the instance to extract into.
+    remote_dir: String,
+}
+
+impl Source {
+    /// Copies the directory at `path`, extracting it into `aws-ec2` in the home directory.
+    #[must_use]
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            remote_dir: String::from(DEFAULT_REMOTE_DIR),
+        }
+    }
+
+    /// Only copies files matching `glob`, may be called multiple times.
+    #[must_use]
+    pub fn include(mut self, glob: impl Into<String>) -> Self {
+        self.include.push(glob.into());
+        self
+    }
+
+    /// Doesn't copy files matching `glob`, may be called multiple times.
+    #[must_use]
+    pub fn exclude(mut self, glob: impl Into<String>) -> Self {
+        self.exclude.push(glob.into());
+        self
+    }
+
+    /// The directory on the instance to extract into, relative paths are relative to the home
+    /// directory of the login user.
+    #[must_use]
+    pub fn remote_dir(mut self, remote_dir: impl Into<String>) -> Self {
+        self.remote_dir = remote_dir.into();
+        self
+    }
+}
+
+/// Keeps an instance running after the command finishes.
+#[derive(Debug, Clone, Copy)]
+pub struct Linger {
+    duration: Duration,
+    on_failure_only: bool,
+}
+
+impl Linger {
+    #[must_use]
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            on_failure_only: false,
+        }
+    }
+
+    /// Only lingers when the command fails.
+    #[must_use]
+    pub fn on_failure_only(mut self, on_failure_only: bool) -> Self {
+        self.on_failure_only = on_failure_only;
+        self
+    }
+}
+
+/// An instance type and the AMI to run on it.
+#[derive(Debug, Clone)]
+pub struct Target {
+    instance_type: InstanceType,
+    ami: String,
+    /// Overrides [`RunConfig::size`] for this target.
+    size: Option<VolumeSize>,
+    /// Overrides [`RunConfig::user`] for this target.
+    user: Option<String>,
+}
+
+impl Target {
+    /// `ami` is an AMI id, `ssm:<parameter>` for the AMI id held by an SSM parameter,
+    /// `name:[<owner>:]<pattern>` for the latest AMI with a name matching the pattern (owned by
+    /// this account by default), or an alias like `ubuntu-22.04` for the latest AMI of a
+    /// distribution.
+    ///
+    /// Name patterns and aliases are resolved for the architecture of the instance type.
+    #[must_use]
+    pub fn new(instance_type: InstanceType, ami: impl Into<String>) -> Self {
+        Self {
+            instance_type,
+            ami: ami.into(),
+            size: None,
+            user: None,
+        }
+    }
+
+    /// The size of the EBS volume of this target, overriding [`RunConfig::size`].
+    #[must_use]
+    pub fn size(mut self, size: VolumeSize) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// The user to login as on this target, overriding [`RunConfig::user`].
+    #[must_use]
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// The `<instance type>/<ami>` label output from this target is prefixed with.
+    fn label(&self) -> String {
+        format!("{}/{}", self.instance_type.as_str(), self.ami)
+    }
+
+    /// The `<instance type>/<ami>` directory artifacts from this target are put in when running
+    /// multiple targets, with any `/` in the AMI (e.g. of an SSM parameter) replaced.
+    fn artifact_dir(&self) -> std::path::PathBuf {
+        Path::new(self.instance_type.as_str()).join(self.ami.replace('/', "_"))
+    }
+}
+
+/// How the AMI of a [`Target`] is given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AmiSpec {
+    Id(String),
+    /// The SSM parameter holding the AMI id.
+    Parameter(String),
+    /// The latest AMI owned by `owner` with a name matching `pattern`.
+    Name {
+        owner: String,
+        pattern: String,
+    },
+    /// The SSM parameters holding the AMI id for `x86_64` and `arm64` instance types.
+    Alias {
+        x86_64: &'static str,
+        arm64: &'static str,
+    },
+}
+
+impl FromStr for AmiSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // `resolve:ssm:` is what `RunInstances` accepts.
+        if let Some(parameter) = s.strip_prefix("ssm:").or(s.strip_prefix("resolve:ssm:")) {
+            if !parameter.is_empty() {
+                return Ok(Self::Parameter(String::from(parameter)));
+            }
+        } else if let Some(name) = s.strip_prefix("name:") {
+            // AMI names can't contain `:`.
+            let (owner, pattern) = name.split_once(':').unwrap_or((DEFAULT_AMI_OWNER, name));
+            if !owner.is_empty() && !pattern.is_empty() {
+                return Ok(Self::Name {
+                    owner: String::from(owner),
+                    pattern: String::from(pattern),
+                });
+            }
+        } else if s.starts_with("ami-") {
+            return Ok(Self::Id(String::from(s)));
+        } else if let Some((_, x86_64, arm64)) =
+            AMI_ALIASES.iter().find(|(alias, _, _)| *alias == s)
+        {
+            return Ok(Self::Alias { x86_64, arm64 });
+        }
+        let aliases = AMI_ALIASES
+            .iter()
+            .map(|(alias, _, _)| *alias)
+            .collect::<Vec<_>>()
+            .join(", ");
+        Err(format!(
+            "{s:?} is not an AMI id, `ssm:<parameter>`, `name:[<owner>:]<pattern>` or one of the \
+            aliases {aliases}"
+        ))
+    }
+}
+
+/// Which IP address of each instance SSH connects to, see [`RunConfig::connect`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Connect {
+    /// The public IP address if the instance has one, else the private IP address.
+    #[default]
+    Auto,
+    Public,
+    Private,
+}
+
+impl FromStr for Connect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "public" => Ok(Self::Public),
+            "private" => Ok(Self::Private),
+            _ => Err(format!("{s:?} is not one of `auto`, `public` or `private`")),
+        }
+    }
+}
+
+impl std::fmt::Display for Connect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Public => write!(f, "public"),
+            Self::Private => write!(f, "private"),
+        }
+    }
+}
+
+impl serde::Serialize for Connect {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// An SSH server instances are connected to through, see [`RunConfig::jump_host`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JumpHost {
+    user: String,
+    host: String,
+    port: u16,
+    /// A private key file to authenticate with, by default the SSH agent is used.
+    identity: Option<std::path::PathBuf>,
+}
+
+impl JumpHost {
+    #[must_use]
+    pub fn new(user: impl Into<String>, host: impl Into<String>, port: u16) -> Self {
+        Self {
+            user: user.into(),
+            host: host.into(),
+            port,
+            identity: None,
+        }
+    }
+
+    /// A private key file to authenticate with, by default the SSH agent is used.
+    #[must_use]
+    pub fn identity(mut self, identity: impl Into<std::path::PathBuf>) -> Self {
+        self.identity = Some(identity.into());
+        self
+    }
+}
+
+impl FromStr for JumpHost {
+    type Err = String;
+
+    /// Parses `<user>@<host>[:<port>]`, an IPv6 host must be in brackets.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("{s:?} is not of the form `<user>@<host>[:<port>]`");
+        let (user, address) = s.split_once('@').ok_or_else(error)?;
+        let (host, port) = match address.strip_prefix('[') {
+            Some(address) => {
+                let (host, port) = address.split_once(']').ok_or_else(error)?;
+                match port {
+                    "" => (host, None),
+                    port => (host, Some(port.strip_prefix(':').ok_or_else(error)?)),
+                }
+            }
+            None => match address.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+        let port = match port {
+            Some(port) => u16::from_str(port).map_err(|err| format!("{s:?}: {err}"))?,
+            None => EC2_SSH_PORT,
+        };
+        if user.is_empty() || host.is_empty() {
+            return Err(error());
+        }
+        Ok(Self::new(user, host, port))
+    }
+}
+
+impl std::fmt::Display for JumpHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "{}@[{}]:{}", self.user, self.host, self.port)
+        } else {
+            write!(f, "{}@{}:{}", self.user, self.host, self.port)
+        }
+    }
+}
+
+impl serde::Serialize for JumpHost {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// How commands are run on each instance, see [`RunConfig::transport`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportKind {
+    /// Over SSH, as the login user.
+    #[default]
+    Ssh,
+    /// Through the SSM agent with `SendCommand`, as root, so no SSH ingress is needed.
+    Ssm,
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ssh" => Ok(Self::Ssh),
+            "ssm" => Ok(Self::Ssm),
+            _ => Err(format!("{s:?} is not one of `ssh` or `ssm`")),
+        }
+    }
+}
+
+impl std::fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Ssh => "ssh",
+            Self::Ssm => "ssm",
+        })
+    }
+}
+
+impl serde::Serialize for TransportKind {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Where instances are launched, see [`RunConfig::network`].
+///
+/// By default instances are launched into the default VPC of the region.
+#[derive(Debug, Clone, Default)]
+pub struct Network {
+    vpc_id: Option<String>,
+    subnet_id: Option<String>,
+    availability_zone: Option<String>,
+    placement_group: Option<String>,
+    subnet_tags: Vec<(String, String)>,
+}
+
+impl Network {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The VPC to launch into, a subnet of it is selected for each instance.
+    #[must_use]
+    pub fn vpc_id(mut self, vpc_id: impl Into<String>) -> Self {
+        self.vpc_id = Some(vpc_id.into());
+        self
+    }
+
+    /// The subnet to launch into, the security group is created in its VPC.
+    #[must_use]
+    pub fn subnet_id(mut self, subnet_id: impl Into<String>) -> Self {
+        self.subnet_id = Some(subnet_id.into());
+        self
+    }
+
+    /// The availability zone to launch into, used to select a subnet when a VPC is given.
+    #[must_use]
+    pub fn availability_zone(mut self, availability_zone: impl Into<String>) -> Self {
+        self.availability_zone = Some(availability_zone.into());
+        self
+    }
+
+    /// The name of an existing placement group to launch into.
+    #[must_use]
+    pub fn placement_group(mut self, placement_group: impl Into<String>) -> Self {
+        self.placement_group = Some(placement_group.into());
+        self
+    }
+
+    /// Only select subnets of the VPC with this tag, may be called multiple times.
+    #[must_use]
+    pub fn subnet_tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.subnet_tags.push((key.into(), value.into()));
+        self
+    }
+}
+
+/// Launch spot instances rather than on-demand instances, see [`RunConfig::spot`].
+#[derive(Debug, Clone, Default)]
+pub struct Spot {
+    max_price: Option<String>,
+    other_zones: bool,
+    on_demand_fallback: bool,
+}
+
+impl Spot {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The maximum price per hour in USD (e.g. `0.05`), by default the on-demand price.
+    #[must_use]
+    pub fn max_price(mut self, max_price: impl Into<String>) -> Self {
+        self.max_price = Some(max_price.into());
+        self
+    }
+
+    /// When there is no spot capacity, try each availability zone of the region in turn (each
+    /// zone with a matching subnet when [`Network::vpc_id`] is set).
+    #[must_use]
+    pub fn other_zones(mut self, other_zones: bool) -> Self {
+        self.other_zones = other_zones;
+        self
+    }
+
+    /// When there is no spot capacity, or the spot instance is interrupted, launch an on-demand
+    /// instance instead.
+    #[must_use]
+    pub fn on_demand_fallback(mut self, on_demand_fallback: bool) -> Self {
+        self.on_demand_fallback = on_demand_fallback;
+        self
+    }
+}
+
+/// The purchasing option an instance was launched with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Market {
+    OnDemand,
+    Spot,
+}
+
+impl std::fmt::Display for Market {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OnDemand => write!(f, "on-demand"),
+            Self::Spot => write!(f, "spot"),
+        }
+    }
+}
+
+/// A named command run as part of a job.
+#[derive(Debug, Clone)]
+pub struct Step {
+    name: String,
+    command: String,
+    continue_on_error: bool,
+    always: bool,
+    /// Overrides [`RunConfig::command_timeout`] for this step.
+    timeout: Option<Duration>,
+}
+
+impl Step {
+    #[must_use]
+    pub fn new(name: impl Into<String>, command: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            command: command.into(),
+            continue_on_error: false,
+            always: false,
+            timeout: None,
+        }
+    }
+
+    /// Don't fail the job or skip the later steps if this step fails.
+    #[must_use]
+    pub fn continue_on_error(mut self, continue_on_error: bool) -> Self {
+        self.continue_on_error = continue_on_error;
+        self
+    }
+
+    /// Run this step even if an earlier step failed, e.g. to clean up.
+    #[must_use]
+    pub fn always(mut self, always: bool) -> Self {
+        self.always = always;
+        self
+    }
+
+    /// The timeout of this step, overriding [`RunConfig::command_timeout`].
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// How a step ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StepStatus {
+    Succeeded,
+    /// Exited with a non-zero code, `true` if the failure was ignored as the step continues on
+    /// error.
+    Failed(i32, bool),
+    /// Timed out after the duration and was killed.
+    TimedOut(Duration, bool),
+    /// Not run as an earlier step failed.
+    Skipped,
+}
+
+impl std::fmt::Display for StepStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ignored = |ignored: &bool| if *ignored { " (ignored)" } else { "" };
+        match self {
+            Self::Succeeded => write!(f, "succeeded"),
+            Self::Failed(code, ignored_) => write!(f, "failed with {code}{}", ignored(ignored_)),
+            Self::TimedOut(timeout, ignored_) => {
+                write!(f, "timed out after {timeout:?}{}", ignored(ignored_))
+            }
+            Self::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// The outcome of a step on a target.
+#[derive(Debug, Clone)]
+struct StepReport {
+    name: String,
+    status: StepStatus,
+    /// When the step started, `None` if it was skipped.
+    started: Option<std::time::SystemTime>,
+    duration: Option<Duration>,
+}
+
+/// The instances to launch and what to run on them.
+///
+/// Built by chaining methods on [`RunConfig::new`], only the targets are required.
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    targets: Vec<Target>,
+    steps: Vec<Step>,
+    command: Option<String>,
+    source: Option<Source>,
+    key_name: Option<String>,
+    security_group: SecurityGroup,
+    launch_timeout: Duration,
+    ssh_timeout: Duration,
+    transfer_timeout: Duration,
+    command_timeout: Duration,
+    deadline: Option<Duration>,
+    size: VolumeSize,
+    linger: Option<Linger>,
+    user: Option<String>,
+    verify_host_key: bool,
+    artifacts: Vec<Artifact>,
+    separate_artifacts: Option<bool>,
+    spot: Option<Spot>,
+    network: Network,
+    connect: Connect,
+    jump_host: Option<JumpHost>,
+    transport: TransportKind,
+    instance_profile: Option<String>,
+    ssm_bucket: Option<String>,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            steps: Vec::new(),
+            command: None,
+            source: None,
+            key_name: None,
+            security_group: SecurityGroup::default(),
+            launch_timeout: Duration::from_secs(DEFAULT_LAUNCH_TIMEOUT_SECS),
+            ssh_timeout: Duration::from_secs(DEFAULT_SSH_TIMEOUT_SECS),
+            transfer_timeout: Duration::from_secs(DEFAULT_TRANSFER_TIMEOUT_SECS),
+            command_timeout: Duration::from_secs(DEFAULT_COMMAND_TIMEOUT_SECS),
+            deadline: None,
+            size: DEFAULT_SIZE,
+            linger: None,
+            user: None,
+            verify_host_key: true,
+            artifacts: Vec::new(),
+            separate_artifacts: None,
+            spot: None,
+            network: Network::default(),
+            connect: Connect::default(),
+            jump_host: None,
+            transport: TransportKind::default(),
+            instance_profile: None,
+            ssm_bucket: None,
+        }
+    }
+}
+
+impl RunConfig {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The steps run on each instance.
+    fn all_steps(&self) -> Vec<Step> {
+        let mut steps = self.steps.clone();
+        match &self.command {
+            Some(command) => steps.push(Step::new("command", command)),
+            None if steps.is_empty() => steps.push(Step::new("command", DEFAULT_COMMAND)),
+            None => {}
+        }
+        steps
+    }
+
+    /// Adds a target to run on, all targets are run concurrently.
+    #[must_use]
+    pub fn target(mut self, target: Target) -> Self {
+        self.targets.push(target);
+        self
+    }
+
+    /// Adds a step to run on each instance, steps are run in order in the same directory.
+    ///
+    /// If a step fails the later steps are skipped, unless the step continues on error or the
+    /// later steps always run.
+    #[must_use]
+    pub fn step(mut self, step: Step) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// A command to run on each instance after the steps, as a step named `command`.
+    ///
+    /// Without steps or a command [`DEFAULT_COMMAND`] is run.
+    #[must_use]
+    pub fn command(mut self, command: impl Into<String>) -> Self {
+        self.command = Some(command.into());
+        self
+    }
+
+    /// A local directory to copy to each instance before running the command.
+    #[must_use]
+    pub fn source(mut self, source: Source) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// The name of the key pair to create, by default generated from the run id.
+    #[must_use]
+    pub fn key_name(mut self, key_name: impl Into<String>) -> Self {
+        self.key_name = Some(key_name.into());
+        self
+    }
+
+    #[must_use]
+    pub fn security_group(mut self, security_group: SecurityGroup) -> Self {
+        self.security_group = security_group;
+        self
+    }
+
+    /// The timeout for launching each instance and waiting for it to be running, also used when
+    /// terminating instances and deleting resources.
+    #[must_use]
+    pub fn launch_timeout(mut self, timeout: Duration) -> Self {
+        self.launch_timeout = timeout;
+        self
+    }
+
+    /// The timeout for connecting SSH to each instance, including waiting for it to print its
+    /// host keys.
+    #[must_use]
+    pub fn ssh_timeout(mut self, timeout: Duration) -> Self {
+        self.ssh_timeout = timeout;
+        self
+    }
+
+    /// The timeout for each transfer of the source or artifacts.
+    #[must_use]
+    pub fn transfer_timeout(mut self, timeout: Duration) -> Self {
+        self.transfer_timeout = timeout;
+        self
+    }
+
+    /// The timeout for each step, a step which times out fails.
+    #[must_use]
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    /// Where to launch instances.
+    #[must_use]
+    pub fn network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Which IP address of each instance SSH connects to.
+    #[must_use]
+    pub fn connect(mut self, connect: Connect) -> Self {
+        self.connect = connect;
+        self
+    }
+
+    /// Connect to each instance through an SSH server, e.g. a bastion in the VPC.
+    ///
+    /// Its host key is verified against `~/.ssh/known_hosts`, unless host key verification is
+    /// disabled.
+    #[must_use]
+    pub fn jump_host(mut self, jump_host: JumpHost) -> Self {
+        self.jump_host = Some(jump_host);
+        self
+    }
+
+    /// How commands are run on each instance.
+    ///
+    /// [`TransportKind::Ssm`] needs the SSM agent on the AMI, an instance profile allowing it to
+    /// register (see [`RunConfig::instance_profile`]) and a client given with
+    /// [`Runner::ssm_client`].
+    #[must_use]
+    pub fn transport(mut self, transport: TransportKind) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// The name of an IAM instance profile to launch instances with.
+    #[must_use]
+    pub fn instance_profile(mut self, instance_profile: impl Into<String>) -> Self {
+        self.instance_profile = Some(instance_profile.into());
+        self
+    }
+
+    /// An S3 bucket to stage files and command output in over [`TransportKind::Ssm`], with a
+    /// client given with [`Runner::s3_client`].
+    ///
+    /// Without one files are copied 16 KiB per command, and output beyond what SSM returns
+    /// (24,000 characters of stdout and 8,000 of stderr) is lost. Files are transferred with
+    /// presigned URLs, while the instance profile must allow `s3:PutObject` to the bucket for
+    /// the agent to upload output.
+    #[must_use]
+    pub fn ssm_bucket(mut self, bucket: impl Into<String>) -> Self {
+        self.ssm_bucket = Some(bucket.into());
+        self
+    }
+
+    /// Launch spot instances rather than on-demand instances.
+    #[must_use]
+    pub fn spot(mut self, spot: Spot) -> Self {
+        self.spot = Some(spot);
+        self
+    }
+
+    /// The time [`Runner::run`] may take in total, after which it stops and returns
+    /// [`MainError::DeadlineExceeded`].
+    #[must_use]
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    #[must_use]
+    pub fn size(mut self, size: VolumeSize) -> Self {
+        self.size = size;
+        self
+    }
+
+    #[must_use]
+    pub fn linger(mut self, linger: Linger) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+
+    /// The user to login as over SSH, by default inferred from the owner and name of each AMI.
+    #[must_use]
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// Whether to verify the SSH host key of each instance against the keys it prints to its
+    /// console, by default `true`.
+    #[must_use]
+    pub fn verify_host_key(mut self, verify_host_key: bool) -> Self {
+        self.verify_host_key = verify_host_key;
+        self
+    }
+
+    /// Files to download from each instance after the command finishes, may be called multiple
+    /// times.
+    #[must_use]
+    pub fn artifact(mut self, artifact: Artifact) -> Self {
+        self.artifacts.push(artifact);
+        self
+    }
+
+    /// Whether the artifacts from each target are put in `<local-dir>/<instance>/<ami>`, by
+    /// default when there is more than one target.
+    ///
+    /// Set when the targets of a run are split between runners (e.g. by region), so the layout
+    /// depends on the total number of targets.
+    #[must_use]
+    pub fn separate_artifacts(mut self, separate_artifacts: bool) -> Self {
+        self.separate_artifacts = Some(separate_artifacts);
+        self
+    }
+}
+
+type SdkResponse = http::response::Response<aws_smithy_http::body::SdkBody>;
+pub(crate) type SdkError<E> = aws_smithy_http::result::SdkError<E, SdkResponse>;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum MainError {
+    #[error("Failed to create key pair: {0}")]
+    CreateKeyPair(SdkError<aws_sdk_ec2::operation::create_key_pair::CreateKeyPairError>),
+    #[error("Created key pair missing key material.")]
+    CreateKeyPairMaterial,
+    #[error("Failed to create security group: {0}")]
+    CreateSecurityGroup(
+        SdkError<aws_sdk_ec2::operation::create_security_group::CreateSecurityGroupError>,
+    ),
+    #[error("Created security group missing id.")]
+    CreateSecurityGroupId,
+    #[error("Failed to detect the public IP address of this machine, use `--ssh-cidr` to set the addresses to allow SSH from.")]
+    PublicIpDetect,
+    #[error("Failed to create security group ingress rule: {0}")]
+    AuthorizeSecurityGroupIngress(SdkError<aws_sdk_ec2::operation::authorize_security_group_ingress::AuthorizeSecurityGroupIngressError>),
+    #[error("Failed to run instances: {0}")]
+    RunInstances(SdkError<aws_sdk_ec2::operation::run_instances::RunInstancesError>),
+    #[error("Failed to describe subnets: {0}")]
+    DescribeSubnets(SdkError<aws_sdk_ec2::operation::describe_subnets::DescribeSubnetsError>),
+    #[error("Subnet {0} not found.")]
+    SubnetNotFound(String),
+    #[error("No available subnet in VPC {0} matches the availability zone and subnet tags.")]
+    NoSubnet(String),
+    #[error("Failed to describe availability zones: {0}")]
+    DescribeAvailabilityZones(SdkError<aws_sdk_ec2::operation::describe_availability_zones::DescribeAvailabilityZonesError>),
+    #[error("Spot instance {0} was interrupted: {1}")]
+    SpotInterrupted(String, String),
+    #[error("Missing instance id from run instances.")]
+    RunInstancesInstanceId,
+    #[error("Instance failed to enter running state within the launch timeout.")]
+    LaunchTimeout,
+    #[error("Failed to describe instance status: {0}")]
+    DescribeInstanceStatus(SdkError<aws_sdk_ec2::operation::describe_instance_status::DescribeInstanceStatusError>),
+    #[error("Missing state from describe instance status.")]
+    DescribeInstanceStatusState,
+    #[error("Failed to describe instances: {0}")]
+    DescribeInstances(SdkError<aws_sdk_ec2::operation::describe_instances::DescribeInstancesError>),
+    #[error("Missing public ip address from describe instances, use `--connect private` to connect to the private ip address.")]
+    DescribeInstancesPublicIpAddress,
+    #[error("Missing instance from describe instances.")]
+    DescribeInstancesInstance,
+    #[error("Missing private ip address from describe instances.")]
+    DescribeInstancesPrivateIpAddress,
+    #[error("Failed to parse ip address: {0}")]
+    IpParse(std::net::AddrParseError),
+    #[error("Timed out waiting for SSH to be ready: {0}")]
+    SshReadyTimeout(std::io::Error),
+    #[error("Failed to connect to jump host {0}: {1}")]
+    JumpHostConnect(String, std::io::Error),
+    #[error("Timed out connecting to jump host {0}.")]
+    JumpHostTimeout(String),
+    #[error("Jump host {0} key is not in `~/.ssh/known_hosts`, or doesn't match it.")]
+    JumpHostKey(String),
+    #[error("Failed SSH auth to jump host {0}: {1}")]
+    JumpHostAuth(String, std::io::Error),
+    #[error("Failed to open a channel through the jump host: {0}")]
+    JumpHostChannel(std::io::Error),
+    #[error("Failed to create SSH session: {0}")]
+    SshSession(std::io::Error),
+    #[error("Failed SSH handshake: {0}")]
+    SshHandshake(std::io::Error),
+    #[error("Timed out connecting SSH within the SSH timeout.")]
+    SshTimeout,
+    #[error("Failed to setup SSH auth: {0}")]
+    SshAuthSetup(std::io::Error),
+    #[error("Failed to get console output: {0}")]
+    GetConsoleOutput(SdkError<aws_sdk_ec2::operation::get_console_output::GetConsoleOutputError>),
+    #[error("Failed to decode console output: {0}")]
+    ConsoleOutputDecode(aws_smithy_types::base64::DecodeError),
+    #[error("Instance did not print its SSH host keys to its console within timeout, use `--insecure-skip-host-key-check` to skip verification.")]
+    HostKeysTimeout,
+    #[error("SSH host key {0} does not match any host key printed to the instance console.")]
+    HostKeyMismatch(String),
+    #[error("Failed SSH auth as any of {0:?}.")]
+    SshAuthFailed(Vec<String>),
+    #[error("No SSM client given, one is needed for the SSM transport and to resolve AMIs from SSM parameters or aliases.")]
+    SsmClientMissing,
+    #[error("No S3 client given for the SSM bucket.")]
+    S3ClientMissing,
+    #[error("SSM request failed: {0}")]
+    Ssm(ssm::Error),
+    #[error("SSM agent on instance {0} not online within the SSH timeout, the AMI needs the agent and `--instance-profile` must allow it to register.")]
+    SsmAgentTimeout(String),
+    #[error("Failed to write file over SSM: {0}: {1}")]
+    SsmWriteFailed(i32, String),
+    #[error("Invalid `--include` or `--exclude` glob: {0}")]
+    ArchiveGlob(ignore::Error),
+    #[error("Failed to read directory: {0}")]
+    ReadDir(ignore::Error),
+    #[error("Failed to append directory to source archive: {0}")]
+    AppendDir(std::io::Error),
+    #[error("Failed to append file to source archive: {0}")]
+    AppendFile(std::io::Error),
+    #[error("Failed to complete archive: {0}")]
+    CompleteArchive(std::io::Error),
+    #[error("Failed to start scp: {0}")]
+    ScpSend(std::io::Error),
+    #[error("Failed to write to scp: {0}")]
+    ScpWrite(std::io::Error),
+    #[error("Failed to send eof to scp: {0}")]
+    ScpSendEof(std::io::Error),
+    #[error("Failed to wait on eof on scp: {0}")]
+    ScpWaitEof(std::io::Error),
+    #[error("Failed to close scp: {0}")]
+    ScpClose(std::io::Error),
+    #[error("Failed to wait on close on scp: {0}")]
+    ScpWaitClose(std::io::Error),
+    #[error("Failed to exec command: {0}")]
+    Exec(ExecError),
+    #[error("Failed to decompress archive: {0}")]
+    DecompressFailed(i32),
+    #[error("Failed to archive artifacts: {0}")]
+    ArtifactExec(ExecError),
+    #[error("Failed to archive artifacts {0:?}: {1}")]
+    ArtifactFailed(String, i32),
+    #[error("Failed to unpack artifacts: {0}")]
+    ArtifactUnpack(std::io::Error),
+    #[error("Timed out transferring files within the transfer timeout.")]
+    TransferTimeout,
+    #[error("Run exceeded its deadline of {0:?}.")]
+    DeadlineExceeded(Duration),
+    #[error("Failed to terminate instances: {0}")]
+    TerminateInstances(SdkError<aws_sdk_ec2::operation::terminate_instances::TerminateInstancesError>),
+    #[error("Failed to delete key pair: {0}")]
+    DeleteKeyPair(SdkError<aws_sdk_ec2::operation::delete_key_pair::DeleteKeyPairError>),
+    #[error("Instance failed to enter terminated state within timeout.")]
+    TerminateTimeout,
+    #[error("Failed to delete security group: {0}")]
+    DeleteSecurityGroup(SdkError<aws_sdk_ec2::operation::delete_security_group::DeleteSecurityGroupError>),
+    #[error("Security group still in use after timeout.")]
+    DeleteSecurityGroupTimeout,
+    #[error("Failed to describe security groups: {0}")]
+    DescribeSecurityGroups(SdkError<aws_sdk_ec2::operation::describe_security_groups::DescribeSecurityGroupsError>),
+    #[error("Failed to describe key pairs: {0}")]
+    DescribeKeyPairs(SdkError<aws_sdk_ec2::operation::describe_key_pairs::DescribeKeyPairsError>),
+    #[error("Failed to describe images: {0}")]
+    DescribeImages(SdkError<aws_sdk_ec2::operation::describe_images::DescribeImagesError>),
+    #[error("Image {0} not found.")]
+    DescribeImagesImage(String),
+    #[error("Invalid AMI: {0}")]
+    InvalidAmi(String),
+    #[error("Failed to get AMI id from SSM parameter {0}: {1}")]
+    AmiParameter(
+        String,
+        SdkError<aws_sdk_ssm::operation::get_parameter::GetParameterError>,
+    ),
+    #[error("SSM parameter {0} has no value.")]
+    AmiParameterValue(String),
+    #[error("No available image owned by {0:?} with a name matching {1:?} for {2}.")]
+    AmiNameNotFound(String, String, String),
+    #[error("Failed to describe instance types: {0}")]
+    DescribeInstanceTypes(SdkError<aws_sdk_ec2::operation::describe_instance_types::DescribeInstanceTypesError>),
+    #[error("Instance type {0} supports neither x86_64 nor arm64.")]
+    InstanceTypeArchitecture(String),
+    #[error("Failed to describe instance type offerings: {0}")]
+    DescribeInstanceTypeOfferings(
+        SdkError<aws_sdk_ec2::operation::describe_instance_type_offerings::DescribeInstanceTypeOfferingsError>,
+    ),
+    #[error("Incompatible targets:\n{}", .0.iter().map(|mismatch| format!("  {mismatch}")).collect::<Vec<_>>().join("\n"))]
+    Incompatible(Vec<String>),
+    #[error("Target panicked: {0}")]
+    TargetPanicked(tokio::task::JoinError),
+    #[error("Failed to write private key: {0}")]
+    WritePrivateKey(std::io::Error),
+    #[error("Failed to delete private key: {0}")]
+    DeletePrivateKey(std::io::Error),
+    #[error("Failed to delete some leaked resources.")]
+    GcFailed,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ExecError {
+    #[error("Failed to create channel: {0}")]
+    Channel(std::io::Error),
+    #[error("Timed out creating channel.")]
+    ChannelTimeout,
+    #[error("Failed exec: {0}")]
+    Exec(std::io::Error),
+    #[error("Timed out running exec.")]
+    ExecTimeout,
+    #[error("Failed to read stdout: {0}")]
+    Stdout(std::io::Error),
+    #[error("Failed to read stderr: {0}")]
+    Stderr(std::io::Error),
+    #[error("Failed to close channel: {0}")]
+    Close(std::io::Error),
+    #[error("Failed to get exit code: {0}")]
+    Exit(ssh2::Error),
+    #[error("Failed to get exit signal: {0}")]
+    ExitSignal(ssh2::Error),
+    #[error("SSM request failed: {0}")]
+    Ssm(Box<ssm::Error>),
+}
+
+/// A resource created in the AWS account which must be deleted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Resource {
+    /// The name of a key pair.
+    KeyPair(String),
+    /// The id of a security group.
+    SecurityGroup(String),
+    /// The id of an instance.
+    Instance(String),
+}
+
+impl std::fmt::Display for Resource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::KeyPair(name) => write!(f, "key pair {name}"),
+            Self::SecurityGroup(id) => write!(f, "security group {id}"),
+            Self::Instance(id) => write!(f, "instance {id}"),
+        }
+    }
+}
+
+/// Records resources as they are created so they can be deleted in reverse order of creation on
+/// success, on error, on SIGINT/SIGTERM and on panic.
+#[derive(Debug, Clone, Default)]
+struct Cleanup(std::sync::Arc<std::sync::Mutex<Vec<Resource>>>);
+
+impl Cleanup {
+    /// Records a created resource.
+    fn push(&self, resource: Resource) {
+        info!("Recorded {resource}");
+        self.0.lock().unwrap().push(resource);
+    }
+
+    /// Records a created resource found after the fact, unless it is already recorded.
+    fn push_found(&self, resource: Resource) {
+        let mut resources = self.0.lock().unwrap();
+        if !resources.contains(&resource) {
+            info!("Found unrecorded {resource}");
+            resources.push(resource);
+        }
+    }
+
+    /// The recorded resources in order of creation.
+    fn resources(&self) -> Vec<Resource> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Terminates an instance ahead of the final cleanup, waiting until it is terminated.
+    ///
+    /// If this fails the instance remains recorded and termination is retried by [`Cleanup::run`].
+    async fn terminate(
+        &self,
+        client: &ec2::Client,
+        timeout: &Duration,
+        instance_id: &str,
+    ) -> Result<(), MainError> {
+        terminate_instances(client, timeout, &[String::from(instance_id)]).await?;
+        let resource = Resource::Instance(String::from(instance_id));
+        self.0.lock().unwrap().retain(|r| *r != resource);
+        Ok(())
+    }
+
+    /// Deletes all recorded resources in reverse order of creation.
+    ///
+    /// As instances are always created last they are terminated together first. A failure to
+    /// delete one resource does not stop the deletion of others, errors are logged and `false` is
+    /// returned if any occurred.
+    async fn run(&self, client: &ec2::Client, timeout: &Duration) -> bool {
+        let resources = std::mem::take(&mut *self.0.lock().unwrap());
+        let (instance_ids, resources) = deletion_order(resources);
+        let mut cleaned = true;
+
+        if !instance_ids.is_empty() {
+            if let Err(err) = terminate_instances(client, timeout, &instance_ids).await {
+                error!("Failed to clean up instances {instance_ids:?}: {err}");
+                cleaned = false;
+            }
+        }
+
+        for resource in &resources {
+            let result = match resource {
+                Resource::Instance(_) => unreachable!("instances are terminated first"),
+                Resource::SecurityGroup(id) => delete_security_group(client, timeout, id).await,
+                Resource::KeyPair(name) => delete_key_pair(client, name).await,
+            };
+            if let Err(err) = result {
+                error!("Failed to clean up {resource}: {err}");
+                cleaned = false;
+            }
+        }
+        cleaned
+    }
+}
+
+/// Splits recorded resources into the ids of the instances, which are terminated together first,
+/// and the other resources in the reverse order of creation they are then deleted in.
+fn deletion_order(resources: Vec<Resource>) -> (Vec<String>, Vec<Resource>) {
+    let (instances, mut others): (Vec<_>, Vec<_>) = resources
+        .into_iter()
+        .partition(|resource| matches!(resource, Resource::Instance(_)));
+    others.reverse();
+    let instance_ids = instances
+        .into_iter()
+        .filter_map(|resource| match resource {
+            Resource::Instance(id) => Some(id),
+            _ => None,
+        })
+        .collect();
+    (instance_ids, others)
+}
+
+/// Launches instances and runs commands on them as described by a [`RunConfig`].
+///
+/// The key pair and security group are created on the first launch and shared by all instances.
+/// Every resource created is recorded, so [`Runner::cleanup`] must be called once finished
+/// (including after an error) to delete them.
+#[derive(Debug, Clone)]
+pub struct Runner {
+    client: ec2::Client,
+    config: Arc<RunConfig>,
+    /// Identifies the resources created by this run.
+    run_id: String,
+    key_name: String,
+    cleanup: Cleanup,
+    archive: Arc<tokio::sync::OnceCell<Vec<u8>>>,
+    key_material: Arc<tokio::sync::OnceCell<String>>,
+    security_group_id: Arc<tokio::sync::OnceCell<String>>,
+    /// The VPC instances are launched into, `None` for the default VPC.
+    vpc_id: Arc<tokio::sync::OnceCell<Option<String>>>,
+    ssm: Option<aws_sdk_ssm::Client>,
+    s3: Option<aws_sdk_s3::Client>,
+    /// The task running each target with its index, shared so they can be stopped before
+    /// cleaning up.
+    targets: Arc<tokio::sync::Mutex<tokio::task::JoinSet<TargetResult>>>,
+}
+
+/// The exit code, market and step reports of running a target.
+type TargetResult = Result<(Option<i32>, Market, Vec<StepReport>), MainError>;
+
+impl Runner {
+    #[must_use]
+    pub fn new(client: ec2::Client, config: RunConfig) -> Self {
+        let run_id = uuid::Uuid::new_v4().to_string();
+        let key_name = config
+            .key_name
+            .clone()
+            .unwrap_or_else(|| format!("{TAG_CREATED_BY_VALUE}-{run_id}"));
+        Self {
+            client,
+            config: Arc::new(config),
+            run_id,
+            key_name,
+            cleanup: Cleanup::default(),
+            archive: Arc::default(),
+            key_material: Arc::default(),
+            security_group_id: Arc::default(),
+            vpc_id: Arc::default(),
+            ssm: None,
+            s3: None,
+            targets: Arc::default(),
+        }
+    }
+
+    /// Sets the client used to run commands when [`RunConfig::transport`] is
+    /// [`TransportKind::Ssm`], and to resolve AMIs from SSM parameters and aliases.
+    #[must_use]
+    pub fn ssm_client(mut self, client: aws_sdk_ssm::Client) -> Self {
+        self.ssm = Some(client);
+        self
+    }
+
+    /// Sets the client used to stage files and command output in [`RunConfig::ssm_bucket`].
+    #[must_use]
+    pub fn s3_client(mut self, client: aws_sdk_s3::Client) -> Self {
+        self.s3 = Some(client);
+        self
+    }
+
+    /// The id all resources created by this run are tagged with.
+    #[must_use]
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// Runs the command on every target concurrently, uploading the source before and downloading
+    /// the artifacts after, then terminates the instances.
+    ///
+    /// Returns `None` if the command timed out on any target, else the first non-zero exit code,
+    /// else `0`.
+    ///
+    /// # Errors
+    ///
+    /// The first error of any target, or [`MainError::DeadlineExceeded`] if the run takes longer
+    /// than [`RunConfig::deadline`]. Either way [`Runner::cleanup`] should be called after.
+    ///
+    /// A target which panics fails with [`MainError::TargetPanicked`].
+    pub async fn run(&self) -> Result<Option<i32>, MainError> {
+        let Some(deadline) = self.config.deadline else {
+            return self.run_targets().await;
+        };
+        if let Ok(result) = tokio::time::timeout(deadline, self.run_targets()).await {
+            result
+        } else {
+            // Anything whose creation was aborted is found by its tag on cleanup.
+            self.stop().await;
+            Err(MainError::DeadlineExceeded(deadline))
+        }
+    }
+
+    /// Runs every target concurrently, see [`Runner::run`].
+    async fn run_targets(&self) -> Result<Option<i32>, MainError> {
+        // Archives the source before creating anything, as it may fail.
+        if let Some(source) = &self.config.source {
+            self.archive_data(source).await?;
+        }
+        let mut amis = Vec::with_capacity(self.config.targets.len());
+        for target in &self.config.targets {
+            amis.push(self.resolve_ami(target).await?);
+        }
+        let targets = self.config.targets.iter().zip(&amis).collect::<Vec<_>>();
+        self.check_compatibility(&targets).await?;
+        self.key_material().await?;
+        self.security_group_id().await?;
+
+        let mut labels = Vec::with_capacity(self.config.targets.len());
+        // The index of the target each task runs, by task id.
+        let mut indices = std::collections::HashMap::new();
+        let mut set = self.targets.lock().await;
+        let separate_artifacts = self
+            .config
+            .separate_artifacts
+            .unwrap_or(self.config.targets.len() > 1);
+        for (i, (target, ami)) in targets.into_iter().enumerate() {
+            let label = target.label();
+            let span = info_span!(
+                "target",
+                instance = target.instance_type.as_str(),
+                ami = target.ami
+            );
+            // Artifacts from each target are kept apart so they don't overwrite each other.
+            let artifacts = self
+                .config
+                .artifacts
+                .iter()
+                .map(|artifact| Artifact {
+                    glob: artifact.glob.clone(),
+                    local_dir: if separate_artifacts {
+                        artifact.local_dir.join(target.artifact_dir())
+                    } else {
+                        artifact.local_dir.clone()
+                    },
+                })
+                .collect::<Vec<_>>();
+            let runner = self.clone();
+            let target = target.clone();
+            let ami = ami.clone();
+            let task = set.spawn(
+                async move { runner.run_target(&target, &ami, &artifacts).await }.instrument(span),
+            );
+            indices.insert(task.id(), i);
+            labels.push(label);
+        }
+
+        let mut target_results = labels.iter().map(|_| None).collect::<Vec<_>>();
+        while let Some(joined) = set.join_next_with_id().await {
+            let (id, result) = match joined {
+                Ok((id, result)) => (id, result),
+                Err(err) => (err.id(), Err(MainError::TargetPanicked(err))),
+            };
+            target_results[indices[&id]] = Some(result);
+        }
+        // Every task is joined once, so each target has a result.
+        let target_results = target_results.into_iter().map(Option::unwrap);
+
+        let mut results = Vec::with_capacity(labels.len());
+        let mut reports = Vec::with_capacity(labels.len());
+        for (label, result) in labels.into_iter().zip(target_results) {
+            let result = match result {
+                Ok((code, market, steps)) => {
+                    info!("{label} ({market}) code: {code:?}");
+                    reports.push((label, market, steps));
+                    Ok(code)
+                }
+                Err(err) => {
+                    error!("{label} error: {err}");
+                    Err(err)
+                }
+            };
+            results.push(result);
+        }
+        if !reports.is_empty() {
+            info!("Summary:\n{}", summary_table(&reports));
+        }
+
+        aggregate_codes(results)
+    }
+
+    /// Runs every step on a single target, retrying on an on-demand instance if there is no spot
+    /// capacity or its spot instance is interrupted and [`Spot::on_demand_fallback`] is set.
+    async fn run_target(&self, target: &Target, ami: &str, artifacts: &[Artifact]) -> TargetResult {
+        let spot = self.config.spot.as_ref();
+        match self
+            .run_target_in_market(target, ami, artifacts, spot)
+            .await
+        {
+            Err(err) if spot.is_some_and(|spot| on_demand_fallback(spot, &err)) => {
+                error!("{err}, retrying on an on-demand instance");
+                self.run_target_in_market(target, ami, artifacts, None)
+                    .await
+            }
+            result => result,
+        }
+    }
+
+    /// Runs every step on an instance of the target launched in the given market, then terminates
+    /// it.
+    async fn run_target_in_market(
+        &self,
+        target: &Target,
+        ami: &str,
+        artifacts: &[Artifact],
+        spot: Option<&Spot>,
+    ) -> TargetResult {
+        let mut instance = self.launch_in_market(target, ami, spot).await?;
+        let market = instance.market;
+        let result = if market == Market::Spot {
+            let id = instance.id.clone();
+            tokio::select! {
+                result = self.run_on_instance(&mut instance, artifacts) => result,
+                status = wait_for_spot_interruption(&self.client, &id) => {
+                    Err(MainError::SpotInterrupted(id.clone(), status))
+                }
+            }
+        } else {
+            self.run_on_instance(&mut instance, artifacts).await
+        };
+        // Terminates the instance whether or not the run succeeded, reporting the first error.
+        let terminated = self.terminate(instance).await;
+        let (code, reports) = result?;
+        terminated?;
+        Ok((code, market, reports))
+    }
+
+    /// Uploads the source, runs each step and downloads the artifacts.
+    async fn run_on_instance(
+        &self,
+        instance: &mut Instance,
+        artifacts: &[Artifact],
+    ) -> Result<(Option<i32>, Vec<StepReport>), MainError> {
+        self.upload(instance).await?;
+
+        let result = self.run_steps(instance).await;
+        let succeeded = matches!(result, Ok((Some(0), _)));
+
+        // Downloads every artifact whether or not the steps succeeded. A failed download only
+        // fails the target if the steps succeeded, so it doesn't hide their result.
+        let mut artifact_error = None;
+        for artifact in artifacts {
+            if let Err(err) = self.download(instance, artifact).await {
+                error!("Failed to download artifacts {:?}: {err}", artifact.glob);
+                artifact_error.get_or_insert(err);
+            }
+        }
+
+        if let Some(linger) = self
+            .config
+            .linger
+            .filter(|linger| !linger.on_failure_only || !succeeded)
+        {
+            let connect = match self.config.transport {
+                TransportKind::Ssh => format!(
+                    "ssh -i {}{} {}@{}",
+                    linger_key_path(&self.key_name).display(),
+                    self.config
+                        .jump_host
+                        .as_ref()
+                        .map_or_else(String::new, |jump_host| format!(" -J {jump_host}")),
+                    instance.user,
+                    instance.address
+                ),
+                TransportKind::Ssm => format!("aws ssm start-session --target {}", instance.id),
+            };
+            info!(
+                "Lingering for {:?}, connect with `{connect}`",
+                linger.duration
+            );
+            tokio::time::sleep(linger.duration).await;
+        }
+
+        match artifact_error {
+            Some(err) if succeeded => Err(err),
+            _ => result,
+        }
+    }
+
+    /// Runs each step on the instance, returning the code of the first failed step.
+    async fn run_steps(
+        &self,
+        instance: &Instance,
+    ) -> Result<(Option<i32>, Vec<StepReport>), MainError> {
+        let mut code = Some(0);
+        let mut failed = false;
+        let mut reports = Vec::new();
+        for step in self.config.all_steps() {
+            if failed && !step.always {
+                info!("Skipping step {:?}", step.name);
+                reports.push(StepReport {
+                    name: step.name,
+                    status: StepStatus::Skipped,
+                    started: None,
+                    duration: None,
+                });
+                continue;
+            }
+
+            info!("Running step {:?}", step.name);
+            let started = std::time::SystemTime::now();
+            let start = Instant::now();
+            let timeout = step.timeout.unwrap_or(self.config.command_timeout);
+            let step_code = self.exec_timeout(instance, &step.command, &timeout).await?;
+            let duration = start.elapsed();
+            let status = match step_code {
+                Some(0) => StepStatus::Succeeded,
+                Some(step_code) => StepStatus::Failed(step_code, step.continue_on_error),
+                None => StepStatus::TimedOut(timeout, step.continue_on_error),
+            };
+            if status == StepStatus::Succeeded {
+                info!("Step {:?} {status} in {duration:.1?}", step.name);
+            } else {
+                error!("Step {:?} {status} in {duration:.1?}", step.name);
+            }
+            if status != StepStatus::Succeeded && !step.continue_on_error {
+                if !failed {
+                    code = step_code;
+                }
+                failed = true;
+            }
+            reports.push(StepReport {
+                name: step.name,
+                status,
+                started: Some(started),
+                duration: Some(duration),
+            });
+        }
+        Ok((code, reports))
+    }
+
+    /// Launches an instance of the target and connects to it, over SSH unless
+    /// [`RunConfig::transport`] is [`TransportKind::Ssm`].
+    ///
+    /// # Errors
+    ///
+    /// If resolving the AMI fails, the instance type and AMI are incompatible, creating the key
+    /// pair or security group fails, launching the instance fails or connecting to it fails.
+    pub async fn launch(&self, target: &Target) -> Result<Instance, MainError> {
+        let ami = self.resolve_ami(target).await?;
+        self.check_compatibility(&[(target, &ami)]).await?;
+        self.launch_in_market(target, &ami, self.config.spot.as_ref())
+            .await
+    }
+
+    /// Launches an instance like [`Runner::launch`] with the resolved `ami`, as a spot instance
+    /// if `spot` is given.
+    async fn launch_in_market(
+        &self,
+        target: &Target,
+        ami: &str,
+        spot: Option<&Spot>,
+    ) -> Result<Instance, MainError> {
+        let ssm = match self.config.transport {
+            TransportKind::Ssh => None,
+            TransportKind::Ssm => {
+                // Checked before launching so a missing client doesn't waste an instance.
+                self.ssm_bucket()?;
+                Some(self.ssm.as_ref().ok_or(MainError::SsmClientMissing)?)
+            }
+        };
+        // Output is still labelled with the AMI as given.
+        let prefix = format!("[{}] ", target.label());
+        let target = &Target {
+            ami: String::from(ami),
+            ..target.clone()
+        };
+
+        // Over SSM commands are run as root, so there is no login user.
+        let users = match (ssm, target.user.as_ref().or(self.config.user.as_ref())) {
+            (Some(_), _) => Vec::new(),
+            (None, Some(user)) => vec![user.clone()],
+            (None, None) => infer_users(&self.client, &target.ami).await?,
+        };
+        let key_material = self.key_material().await?;
+        let security_group_id = self.security_group_id().await?;
+
+        let ((addresses, id), market) = if let Some(spot) = spot {
+            let launched = self.launch_spot(target, security_group_id, spot).await?;
+            (launched, Market::Spot)
+        } else {
+            let launched = self
+                .launch_instance(target, security_group_id, None, None)
+                .await?;
+            (launched, Market::OnDemand)
+        };
+        info!("Launched {market} instance {id}");
+        let address = String::from(addresses.connect(self.config.connect)?);
+
+        let (transport, user): (Box<dyn Transport>, _) = if let Some(client) = ssm {
+            let session =
+                ssm::Session::connect(client, self.ssm_bucket()?, &id, &self.config.ssh_timeout)
+                    .await?;
+            (Box::new(session), String::from("root"))
+        } else {
+            let (session, user) = self
+                .connect_ssh(&id, &address, key_material, &users)
+                .await?;
+            (Box::new(session), user)
+        };
+
+        Ok(Instance {
+            id,
+            address,
+            public_ip: addresses.public,
+            private_ip: addresses.private,
+            user,
+            transport,
+            prefix,
+            remote_dir: None,
+            market,
+        })
+    }
+
+    /// The bucket files and command output are staged in over SSM, if any.
+    fn ssm_bucket(&self) -> Result<Option<ssm::Bucket>, MainError> {
+        match (&self.config.ssm_bucket, &self.s3) {
+            (Some(name), Some(client)) => Ok(Some(ssm::Bucket {
+                client: client.clone(),
+                name: name.clone(),
+                prefix: format!("{TAG_CREATED_BY_VALUE}/{}", self.run_id),
+            })),
+            (Some(_), None) => Err(MainError::S3ClientMissing),
+            (None, _) => Ok(None),
+        }
+    }
+
+    /// The id of the AMI of `target`, resolving SSM parameters, name patterns and aliases.
+    async fn resolve_ami(&self, target: &Target) -> Result<String, MainError> {
+        #[allow(clippy::enum_glob_use)]
+        use MainError::*;
+
+        let parameter = match AmiSpec::from_str(&target.ami).map_err(InvalidAmi)? {
+            AmiSpec::Id(id) => return Ok(id),
+            AmiSpec::Parameter(parameter) => parameter,
+            AmiSpec::Name { owner, pattern } => {
+                let architecture = architecture(&self.client, &target.instance_type).await?;
+                let ami = latest_image(&self.client, &owner, &pattern, &architecture).await?;
+                info!("Resolved AMI {} to {ami}", target.ami);
+                return Ok(ami);
+            }
+            AmiSpec::Alias { x86_64, arm64 } => {
+                match architecture(&self.client, &target.instance_type).await? {
+                    ec2::types::ArchitectureType::Arm64 => String::from(arm64),
+                    _ => String::from(x86_64),
+                }
+            }
+        };
+        info!("Getting AMI id from SSM parameter {parameter}");
+        let ssm = self.ssm.as_ref().ok_or(SsmClientMissing)?;
+        let response = match ssm.get_parameter().name(&parameter).send().await {
+            Ok(response) => response,
+            Err(err) => return Err(AmiParameter(parameter, err)),
+        };
+        let Some(ami) = response
+            .parameter()
+            .and_then(|parameter| parameter.value())
+            .map(String::from)
+        else {
+            return Err(AmiParameterValue(parameter));
+        };
+        info!("Resolved AMI {} to {ami}", target.ami);
+        Ok(ami)
+    }
+
+    /// Checks each target's instance type is offered in the region and can boot its resolved
+    /// AMI, so mismatches are reported together before anything is created rather than one at a
+    /// time by `RunInstances`.
+    async fn check_compatibility(&self, targets: &[(&Target, &String)]) -> Result<(), MainError> {
+        if targets.is_empty() {
+            return Ok(());
+        }
+        let mut instance_types = targets
+            .iter()
+            .map(|(target, _)| target.instance_type.clone())
+            .collect::<Vec<_>>();
+        instance_types.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        instance_types.dedup();
+        let mut amis = targets
+            .iter()
+            .map(|(_, ami)| (*ami).clone())
+            .collect::<Vec<_>>();
+        amis.sort();
+        amis.dedup();
+
+        let offered = instance_type_offerings(&self.client, &instance_types).await?;
+        let infos = describe_instance_types(&self.client, &offered).await?;
+        let images = describe_images(&self.client, &amis).await?;
+
+        let region = self
+            .client
+            .conf()
+            .region()
+            .map_or("this region", |region| region.as_ref());
+        let mismatches = targets
+            .iter()
+            .flat_map(|(target, ami)| {
+                let instance_type = &target.instance_type;
+                // Only offered instance types are described.
+                let info = infos
+                    .iter()
+                    .find(|info| info.instance_type() == Some(instance_type));
+                let image = images.iter().find(|image| image.image_id() == Some(ami));
+                incompatibilities(instance_type, region, info, ami, image)
+                    .into_iter()
+                    .map(move |mismatch| format!("{}: {mismatch}", target.label()))
+            })
+            .collect::<Vec<_>>();
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(MainError::Incompatible(mismatches))
+        }
+    }
+
+    /// Connects SSH to the instance at `address`, authenticating as the first of `users` which
+    /// succeeds.
+    async fn connect_ssh(
+        &self,
+        id: &str,
+        address: &str,
+        key_material: &str,
+        users: &[String],
+    ) -> Result<(AsyncSession, String), MainError> {
+        // The timeout covers connecting as a whole, so each step is given the time remaining.
+        let deadline = Instant::now() + self.config.ssh_timeout;
+        let host_keys = if self.config.verify_host_key {
+            Some(get_host_keys(&self.client, &remaining(deadline), id).await?)
+        } else {
+            None
+        };
+        let ssh = match &self.config.jump_host {
+            Some(jump_host) => {
+                let timeout = remaining(deadline);
+                connect_through(jump_host, address, &timeout, self.config.verify_host_key).await?
+            }
+            None => connect_direct(address, &remaining(deadline)).await?,
+        };
+        let timeout = remaining(deadline);
+        create_ssh(ssh, &timeout, key_material, users, host_keys.as_ref()).await
+    }
+
+    /// Launches a spot instance, trying each availability zone in turn while there is no spot
+    /// capacity if `spot` allows it.
+    async fn launch_spot(
+        &self,
+        target: &Target,
+        security_group_id: &str,
+        spot: &Spot,
+    ) -> Result<(Addresses, String), MainError> {
+        let zones = if tries_other_zones(spot, &self.config.network) {
+            self.spot_zones().await?
+        } else {
+            Vec::new()
+        };
+        // The zones are tried from the start, so none is tried twice.
+        let mut zones = zones.into_iter();
+        let Some(mut zone) = zones.next() else {
+            return self
+                .launch_instance(target, security_group_id, Some(spot), None)
+                .await;
+        };
+        loop {
+            let result = self
+                .launch_instance(target, security_group_id, Some(spot), Some(&zone))
+                .await;
+            match (result, zones.next()) {
+                (Err(err), Some(next)) if is_spot_capacity_error(&err) => {
+                    info!("No spot capacity in {zone} ({err}), trying {next}");
+                    zone = next;
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+
+    /// The availability zones to try launching spot instances in, only those with a subnet
+    /// matching [`Network`] when launching into a VPC.
+    async fn spot_zones(&self) -> Result<Vec<String>, MainError> {
+        let network = &self.config.network;
+        match &network.vpc_id {
+            Some(vpc_id) => {
+                let filters = subnet_filters(network, vpc_id, None);
+                let subnets = describe_subnets(&self.client, filters).await?;
+                Ok(subnet_zones(&subnets))
+            }
+            None => availability_zones(&self.client).await,
+        }
+    }
+
+    /// Launches an instance of the target in the market given, `zone` overriding
+    /// [`Network::availability_zone`].
+    async fn launch_instance(
+        &self,
+        target: &Target,
+        security_group_id: &str,
+        spot: Option<&Spot>,
+        zone: Option<&str>,
+    ) -> Result<(Addresses, String), MainError> {
+        let subnet_id = self.subnet_id(zone).await?;
+        // The zone of a subnet is fixed, so it is only set when there isn't one.
+        let zone = match subnet_id {
+            Some(_) => None,
+            None => zone.or(self.config.network.availability_zone.as_deref()),
+        };
+        launch_instance(
+            &self.client,
+            &self.cleanup,
+            &self.run_id,
+            &target.instance_type,
+            &target.ami,
+            &self.key_name,
+            security_group_id,
+            &self.config.launch_timeout,
+            &target.size.unwrap_or(self.config.size),
+            spot,
+            zone,
+            subnet_id.as_deref(),
+            self.config.network.placement_group.as_deref(),
+            self.config.connect != Connect::Private,
+            self.config.instance_profile.as_deref(),
+        )
+        .await
+    }
+
+    /// Copies the source directory to the instance, if one is configured.
+    ///
+    /// Later calls to [`Runner::exec`] and [`Runner::download`] are run in the directory it is
+    /// extracted into.
+    ///
+    /// # Errors
+    ///
+    /// If archiving the source fails, or copying or extracting it fails.
+    pub async fn upload(&self, instance: &mut Instance) -> Result<(), MainError> {
+        let Some(source) = &self.config.source else {
+            return Ok(());
+        };
+        let data = self.archive_data(source).await?;
+        let remote_path = format!("/tmp/{}.tar.gz", uuid::Uuid::new_v4());
+        transfer_source(
+            data,
+            &remote_path,
+            &source.remote_dir,
+            instance.transport.as_ref(),
+            &self.config.transfer_timeout,
+            &instance.prefix,
+        )
+        .await?;
+        instance.remote_dir = Some(source.remote_dir.clone());
+        Ok(())
+    }
+
+    /// Runs `command` on the instance, writing its output prefixed by the instance type and AMI.
+    ///
+    /// Returns `None` if the command timed out, in which case its process group is killed, else its
+    /// exit code.
+    ///
+    /// # Errors
+    ///
+    /// If running the command fails.
+    pub async fn exec(&self, instance: &Instance, command: &str) -> Result<Option<i32>, MainError> {
+        self.exec_timeout(instance, command, &self.config.command_timeout)
+            .await
+    }
+
+    /// Runs `command` like [`Runner::exec`] with the given timeout.
+    async fn exec_timeout(
+        &self,
+        instance: &Instance,
+        command: &str,
+        timeout: &Duration,
+    ) -> Result<Option<i32>, MainError> {
+        let command = match &instance.remote_dir {
+            Some(remote_dir) => format!("cd {} || exit 1\n{command}", shell_quote(remote_dir)),
+            None => String::from(command),
+        };
+        instance
+            .transport
+            .exec(&command, timeout, &instance.prefix, false)
+            .await
+            .map(|(code, _)| code)
+            .map_err(MainError::Exec)
+    }
+
+    /// Downloads the files matching `artifact` from the instance.
+    ///
+    /// # Errors
+    ///
+    /// If archiving the files on the instance fails, or unpacking them locally fails.
+    pub async fn download(
+        &self,
+        instance: &Instance,
+        artifact: &Artifact,
+    ) -> Result<(), MainError> {
+        download_artifact(
+            artifact,
+            instance.remote_dir.as_deref(),
+            instance.transport.as_ref(),
+            &self.config.transfer_timeout,
+            &instance.prefix,
+        )
+        .await
+    }
+
+    /// Terminates the instance, waiting until it is terminated.
+    ///
+    /// # Errors
+    ///
+    /// If terminating the instance fails, it is then terminated by [`Runner::cleanup`].
+    pub async fn terminate(&self, instance: Instance) -> Result<(), MainError> {
+        self.cleanup
+            .terminate(&self.client, &self.config.launch_timeout, &instance.id)
+            .await
+    }
+
+    /// Aborts the targets still running, waiting until they have stopped so they can't record any
+    /// more resources.
+    async fn stop(&self) {
+        self.targets.lock().await.shutdown().await;
+    }
+
+    /// Deletes every resource created by this run which hasn't already been deleted, first
+    /// stopping any targets still running.
+    ///
+    /// Resources are only recorded once the request creating them returns, so those whose request
+    /// was aborted (e.g. when [`RunConfig::deadline`] is exceeded) are found by the tag of the run.
+    ///
+    /// Returns `false` if finding or deleting any resource failed, the errors are logged.
+    pub async fn cleanup(&self) -> bool {
+        self.stop().await;
+        let found = match tagged_resources(&self.client, run_id_filter(&self.run_id)).await {
+            Ok(resources) => {
+                for (resource, _) in resources {
+                    self.cleanup.push_found(resource);
+                }
+                true
+            }
+            Err(err) => {
+                error!("Failed to find the resources of run {}: {err}", self.run_id);
+                false
+            }
+        };
+        let deleted = self
+            .cleanup
+            .run(&self.client, &self.config.launch_timeout)
+            .await;
+        found && deleted
+    }
+
+    /// The archive of `source`, only created once.
+    async fn archive_data(&self, source: &Source) -> Result<&[u8], MainError> {
+        let init = async || archive(source);
+        self.archive.get_or_try_init(init).await.map(Vec::as_slice)
+    }
+
+    /// The private key of the key pair, creating the key pair on the first call.
+    async fn key_material(&self) -> Result<&str, MainError> {
+        #[allow(clippy::enum_glob_use)]
+        use MainError::*;
+
+        let init = async || {
+            info!("Creating SSH key pair");
+            let builder = self
+                .client
+                .create_key_pair()
+                .key_name(self.key_name.clone())
+                .tag_specifications(tag_specification(
+                    ec2::types::ResourceType::KeyPair,
+                    &self.run_id,
+                ));
+            let create_key_pair_response = builder.send().await.map_err(CreateKeyPair)?;
+            self.cleanup.push(Resource::KeyPair(self.key_name.clone()));
+
+            // Private key
+            let key_material = create_key_pair_response
+                .key_material
+                .ok_or(CreateKeyPairMaterial)?;
+
+            // When lingering the private key is needed to SSH into the instance.
+            if self.config.linger.is_some() {
+                let key_path = linger_key_path(&self.key_name);
+                write_private_key(&key_path, &key_material).map_err(WritePrivateKey)?;
+                info!("Wrote private key to {}", key_path.display());
+            }
+            Ok(key_material)
+        };
+        self.key_material
+            .get_or_try_init(init)
+            .await
+            .map(String::as_str)
+    }
+
+    /// The id of the security group, creating the security group on the first call.
+    async fn security_group_id(&self) -> Result<&str, MainError> {
+        let (name, ssh_cidrs) = match &self.config.security_group {
+            SecurityGroup::Existing(id) => return Ok(id),
+            SecurityGroup::Create { name, ssh_cidrs } => (name, ssh_cidrs),
+        };
+        let init = async || {
+            let name = name
+                .clone()
+                .unwrap_or_else(|| format!("{TAG_CREATED_BY_VALUE}-{}", self.run_id));
+            // Commands are sent through the SSM agent, which needs no ingress.
+            let ssh_cidrs = if self.config.transport == TransportKind::Ssm {
+                Vec::new()
+            } else if ssh_cidrs.is_empty() {
+                detect_public_ips().await?
+            } else {
+                ssh_cidrs.clone()
+            };
+            let vpc_id = self.vpc_id().await?;
+            create_security_group(
+                &self.client,
+                &self.cleanup,
+                &self.run_id,
+                &name,
+                &ssh_cidrs,
+                vpc_id,
+            )
+            .await
+        };
+        self.security_group_id
+            .get_or_try_init(init)
+            .await
+            .map(String::as_str)
+    }
+
+    /// The VPC instances are launched into, looked up from the subnet if only it is given.
+    async fn vpc_id(&self) -> Result<Option<&str>, MainError> {
+        let network = &self.config.network;
+        let init = async || match (&network.vpc_id, &network.subnet_id) {
+            (Some(vpc_id), _) => Ok(Some(vpc_id.clone())),
+            (None, Some(subnet_id)) => {
+                let filter = ec2::types::Filter::builder()
+                    .name("subnet-id")
+                    .values(subnet_id)
+                    .build();
+                let subnets = describe_subnets(&self.client, vec![filter]).await?;
+                let subnet = subnets
+                    .first()
+                    .ok_or_else(|| MainError::SubnetNotFound(subnet_id.clone()))?;
+                Ok(subnet.vpc_id().map(String::from))
+            }
+            (None, None) => Ok(None),
+        };
+        self.vpc_id
+            .get_or_try_init(init)
+            .await
+            .map(Option::as_deref)
+    }
+
+    /// The subnet to launch an instance into, `zone` overriding [`Network::availability_zone`],
+    /// or `None` to use the default VPC.
+    async fn subnet_id(&self, zone: Option<&str>) -> Result<Option<String>, MainError> {
+        let network = &self.config.network;
+        if let Some(subnet_id) = &network.subnet_id {
+            return Ok(Some(subnet_id.clone()));
+        }
+        let Some(vpc_id) = &network.vpc_id else {
+            return Ok(None);
+        };
+        let filters = subnet_filters(network, vpc_id, zone);
+        let subnets = describe_subnets(&self.client, filters).await?;
+        let subnet = select_subnet(&subnets).ok_or_else(|| MainError::NoSubnet(vpc_id.clone()))?;
+        info!("Selected subnet {subnet} of {vpc_id}");
+        Ok(Some(subnet))
+    }
+}
+
+/// An instance launched by [`Runner::launch`], connected to over SSH or SSM.
+pub struct Instance {
+    id: String,
+    /// The address SSH is connected to.
+    address: String,
+    public_ip: Option<String>,
+    private_ip: Option<String>,
+    /// The user commands are run as.
+    user: String,
+    transport: Box<dyn Transport>,
+    /// Prefixes each line of output from the instance.
+    prefix: String,
+    /// The directory the source was extracted into, commands are run in it.
+    remote_dir: Option<String>,
+    market: Market,
+}
+
+impl Instance {
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The address SSH is connected to, see [`RunConfig::connect`].
+    #[must_use]
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    #[must_use]
+    pub fn public_ip(&self) -> Option<&str> {
+        self.public_ip.as_deref()
+    }
+
+    #[must_use]
+    pub fn private_ip(&self) -> Option<&str> {
+        self.private_ip.as_deref()
+    }
+
+    /// The user commands are run as, `root` over SSM.
+    #[must_use]
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Whether the instance is a spot or on-demand instance.
+    #[must_use]
+    pub fn market(&self) -> Market {
+        self.market
+    }
+}
+
+/// Creates a security group allowing SSH ingress from `ssh_cidrs`, if any, and returns its id.
+async fn create_security_group(
+    client: &ec2::Client,
+    cleanup: &Cleanup,
+    run_id: &str,
+    security_group_name: &str,
+    ssh_cidrs: &[Cidr],
+    vpc_id: Option<&str>,
+) -> Result<String, MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    info!("Creating security groups");
+    // The default settings prevent SSH working.
+    let builder = client
+        .create_security_group()
+        .set_group_name(Some(String::from(security_group_name)))
+        .set_vpc_id(vpc_id.map(String::from))
+        .set_description(Some(String::from(SECURITY_GROUP_DESCRIPTION)))
+        .tag_specifications(tag_specification(
+            ec2::types::ResourceType::SecurityGroup,
+            run_id,
+        ));
+    let create_security_group_response = builder.send().await.map_err(CreateSecurityGroup)?;
+    let security_group_id = create_security_group_response
+        .group_id
+        .ok_or(CreateSecurityGroupId)?;
+    cleanup.push(Resource::SecurityGroup(security_group_id.clone()));
+    if ssh_cidrs.is_empty() {
+        return Ok(security_group_id);
+    }
+
+    // Set inbound rule (the default outbound rule is fine).
+    info!("Setting ingress security group rule for {ssh_cidrs:?}");
+    let (ipv4, ipv6): (Vec<&Cidr>, Vec<&Cidr>) =
+        ssh_cidrs.iter().partition(|cidr| cidr.address.is_ipv4());
+    let permission = ec2::types::IpPermission::builder()
+        .ip_protocol("tcp")
+        .from_port(i32::from(EC2_SSH_PORT))
+        .to_port(i32::from(EC2_SSH_PORT))
+        .set_ip_ranges(Some(
+            ipv4.into_iter()
+                .map(|cidr| {
+                    ec2::types::IpRange::builder()
+                        .cidr_ip(cidr.to_string())
+                        .build()
+                })
+                .collect(),
+        ))
+        .set_ipv6_ranges(Some(
+            ipv6.into_iter()
+                .map(|cidr| {
+                    ec2::types::Ipv6Range::builder()
+                        .cidr_ipv6(cidr.to_string())
+                        .build()
+                })
+                .collect(),
+        ))
+        .build();
+    let builder = client
+        .authorize_security_group_ingress()
+        .set_group_id(Some(security_group_id.clone()))
+        .ip_permissions(permission);
+    builder
+        .send()
+        .await
+        .map_err(AuthorizeSecurityGroupIngress)?;
+
+    Ok(security_group_id)
+}
+
+/// Detects the public IPv4 and IPv6 addresses of this machine.
+///
+/// Machines without IPv6 connectivity only have an IPv4 address, it is an error if neither can be
+/// detected.
+async fn detect_public_ips() -> Result<Vec<Cidr>, MainError> {
+    info!("Detecting public IP addresses");
+    let mut cidrs = Vec::new();
+    for host in [IPV4_ECHO_HOST, IPV6_ECHO_HOST] {
+        match tokio::time::timeout(PUBLIC_IP_DETECT_TIMEOUT, echo_ip(host)).await {
+            Ok(Ok(address)) => cidrs.push(Cidr::from(address)),
+            Ok(Err(err)) => info!("Failed to get public IP address from {host}: {err}"),
+            Err(_) => info!("Timed out getting public IP address from {host}"),
+        }
+    }
+    if cidrs.is_empty() {
+        return Err(MainError::PublicIpDetect);
+    }
+    Ok(cidrs)
+}
+
+/// Asks `host` over HTTP for the IP address requests from this machine appear to come from.
+async fn echo_ip(host: &str) -> std::io::Result<std::net::IpAddr> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::TcpStream::connect((host, 80)).await?;
+    // HTTP/1.0 so the response isn't chunked.
+    let request = format!("GET / HTTP/1.0\r\nHost: {host}\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let (_, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or(ErrorKind::InvalidData)?;
+    std::net::IpAddr::from_str(body.trim())
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Tags identifying a resource as created by this tool in the run `run_id`.
+fn tag_specification(
+    resource_type: ec2::types::ResourceType,
+    run_id: &str,
+) -> ec2::types::TagSpecification {
+    let created_at = ec2::primitives::DateTime::from(std::time::SystemTime::now())
+        .fmt(ec2::primitives::DateTimeFormat::DateTime)
+        .unwrap();
+    ec2::types::TagSpecification::builder()
+        .resource_type(resource_type)
+        .tags(
+            ec2::types::Tag::builder()
+                .key(TAG_CREATED_BY)
+                .value(TAG_CREATED_BY_VALUE)
+                .build(),
+        )
+        .tags(
+            ec2::types::Tag::builder()
+                .key(TAG_RUN_ID)
+                .value(run_id)
+                .build(),
+        )
+        .tags(
+            ec2::types::Tag::builder()
+                .key(TAG_CREATED_AT)
+                .value(created_at)
+                .build(),
+        )
+        .build()
+}
+
+/// The filter matching resources tagged by [`tag_specification`].
+fn created_by_filter() -> ec2::types::Filter {
+    ec2::types::Filter::builder()
+        .name(format!("tag:{TAG_CREATED_BY}"))
+        .values(TAG_CREATED_BY_VALUE)
+        .build()
+}
+
+/// The filter matching resources tagged by [`tag_specification`] for the run `run_id`.
+fn run_id_filter(run_id: &str) -> ec2::types::Filter {
+    ec2::types::Filter::builder()
+        .name(format!("tag:{TAG_RUN_ID}"))
+        .values(run_id)
+        .build()
+}
+
+/// Finds the key pairs, security groups and instances (which haven't been terminated) matching
+/// `filter`, in that order, with their creation times if known.
+async fn tagged_resources(
+    client: &ec2::Client,
+    filter: ec2::types::Filter,
+) -> Result<Vec<(Resource, Option<ec2::primitives::DateTime>)>, MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    let mut resources = Vec::new();
+    info!("Finding key pairs");
+    let builder = client.describe_key_pairs().filters(filter.clone());
+    let describe_key_pairs_response = builder.send().await.map_err(DescribeKeyPairs)?;
+    for key_pair in describe_key_pairs_response.key_pairs().unwrap_or_default() {
+        if let Some(name) = key_pair.key_name() {
+            let resource = Resource::KeyPair(String::from(name));
+            resources.push((resource, key_pair.create_time().copied()));
+        }
+    }
+
+    info!("Finding security groups");
+    let mut next_token = None;
+    loop {
+        let builder = client
+            .describe_security_groups()
+            .filters(filter.clone())
+            .set_next_token(next_token);
+        let describe_security_groups_response =
+            builder.send().await.map_err(DescribeSecurityGroups)?;
+        for group in describe_security_groups_response
+            .security_groups()
+            .unwrap_or_default()
+        {
+            if let Some(id) = group.group_id() {
+                let resource = Resource::SecurityGroup(String::from(id));
+                resources.push((resource, created_at(group.tags())));
+            }
+        }
+        next_token = describe_security_groups_response.next_token;
+        if next_token.is_none() {
+            break;
+        }
+    }
+
+    info!("Finding instances");
+    let mut next_token = None;
+    loop {
+        let builder = client
+            .describe_instances()
+            .filters(filter.clone())
+            .filters(
+                ec2::types::Filter::builder()
+                    .name("instance-state-name")
+                    .values("pending")
+                    .values("running")
+                    .values("stopping")
+                    .values("stopped")
+                    .build(),
+            )
+            .set_next_token(next_token);
+        let describe_instances_response = builder.send().await.map_err(DescribeInstances)?;
+        for reservation in describe_instances_response
+            .reservations()
+            .unwrap_or_default()
+        {
+            for instance in reservation.instances().unwrap_or_default() {
+                if let Some(id) = instance.instance_id() {
+                    let resource = Resource::Instance(String::from(id));
+                    resources.push((resource, instance.launch_time().copied()));
+                }
+            }
+        }
+        next_token = describe_instances_response.next_token;
+        if next_token.is_none() {
+            break;
+        }
+    }
+
+    Ok(resources)
+}
+
+/// The time elapsed since `time`.
+fn age(time: &ec2::primitives::DateTime) -> Duration {
+    let now = ec2::primitives::DateTime::from(std::time::SystemTime::now());
+    Duration::from_secs(u64::try_from(now.secs() - time.secs()).unwrap_or(0))
+}
+
+/// The `created-at` tag amongst `tags`.
+fn created_at(tags: Option<&[ec2::types::Tag]>) -> Option<ec2::primitives::DateTime> {
+    let tag = tags?.iter().find(|tag| tag.key() == Some(TAG_CREATED_AT))?;
+    ec2::primitives::DateTime::from_str(tag.value()?, ec2::primitives::DateTimeFormat::DateTime)
+        .ok()
+}
+
+/// Finds the instances, security groups and key pairs tagged as created by this tool more than
+/// `older_than` ago and deletes them, unless `dry_run`.
+///
+/// Resources without a known creation time are skipped. Returns the resources found.
+///
+/// # Errors
+///
+/// If finding the resources fails, or deleting any of them fails.
+pub async fn gc(
+    client: &ec2::Client,
+    timeout: &Duration,
+    older_than: Duration,
+    dry_run: bool,
+) -> Result<Vec<Resource>, MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    // Resources are pushed in the order they are created by a run, so they are deleted in the
+    // reverse order.
+    let cleanup = Cleanup::default();
+    for (resource, create_time) in tagged_resources(client, created_by_filter()).await? {
+        if create_time.is_some_and(|time| age(&time) > older_than) {
+            cleanup.push(resource);
+        }
+    }
+
+    let resources = cleanup.resources();
+    if dry_run {
+        return Ok(resources);
+    }
+    if cleanup.run(client, timeout).await {
+        Ok(resources)
+    } else {
+        Err(GcFailed)
+    }
+}
+
+/// How [`run_all`] ended.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum RunEnd<T> {
+    /// Every runner finished, with their results combined by [`aggregate_codes`].
+    Finished(Result<Option<i32>, MainError>),
+    /// The shutdown future completed first with this output, so the runners were stopped.
+    Shutdown(T),
+}
+
+/// Runs every runner concurrently until they all finish or `shutdown` completes, then deletes
+/// everything they created.
+///
+/// Returns how the run ended, and `false` if deleting any resource failed. A runner which panics
+/// fails with [`MainError::TargetPanicked`].
+pub async fn run_all<T>(
+    runners: &[Runner],
+    shutdown: impl std::future::Future<Output = T>,
+) -> (RunEnd<T>, bool) {
+    let mut set = tokio::task::JoinSet::new();
+    for runner in runners {
+        let runner = runner.clone();
+        set.spawn(async move { runner.run().await });
+    }
+    let finished = async {
+        let mut results = Vec::new();
+        while let Some(result) = set.join_next().await {
+            results.push(result.unwrap_or_else(|err| Err(MainError::TargetPanicked(err))));
+        }
+        aggregate_codes(results)
+    };
+    let result = tokio::select! {
+        result = finished => Ok(result),
+        output = shutdown => Err(output),
+    };
+    // A runner may be recording a resource on another thread, so every runner is stopped before
+    // taking what was recorded, then each stops its targets in cleaning up.
+    set.shutdown().await;
+
+    let mut cleaned = true;
+    for runner in runners {
+        cleaned &= runner.cleanup().await;
+    }
+    let end = match result {
+        Ok(result) => RunEnd::Finished(result),
+        Err(output) => RunEnd::Shutdown(output),
+    };
+    (end, cleaned)
+}
+
+/// Combines the results of each target into a single result.
+///
+/// Returns the first error, else `None` if any target timed out, else the first non-zero exit
+/// code, else `0`.
+///
+/// # Errors
+///
+/// The first error amongst `results`.
+pub fn aggregate_codes(
+    results: Vec<Result<Option<i32>, MainError>>,
+) -> Result<Option<i32>, MainError> {
+    let codes = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+    let codes = codes.into_iter().collect::<Option<Vec<_>>>();
+    Ok(codes.map(|codes| codes.into_iter().find(|code| *code != 0).unwrap_or(0)))
+}
+
+/// Runs commands on, and writes files to, an instance.
+///
+/// Implemented over SSH by [`AsyncSession`] and over SSM by [`ssm::Session`], see
+/// [`TransportKind`].
+#[async_trait::async_trait]
+trait Transport: Send + Sync {
+    /// Runs `command`, writing its stdout (unless `capture_stdout`) and stderr with each line
+    /// prefixed by `prefix`.
+    ///
+    /// Returns `None` if the command didn't finish within `timeout`, after killing its process
+    /// group, else its exit code, and its stdout if `capture_stdout`.
+    async fn exec(
+        &self,
+        command: &str,
+        timeout: &Duration,
+        prefix: &str,
+        capture_stdout: bool,
+    ) -> Result<(Option<i32>, Vec<u8>), ExecError>;
+
+    /// Writes `data` to `remote_path`.
+    async fn write_file(
+        &self,
+        data: &[u8],
+        remote_path: &str,
+        timeout: &Duration,
+    ) -> Result<(), MainError>;
+}
+
+#[async_trait::async_trait]
+impl Transport for AsyncSession {
+    async fn exec(
+        &self,
+        command: &str,
+        timeout: &Duration,
+        prefix: &str,
+        capture_stdout: bool,
+    ) -> Result<(Option<i32>, Vec<u8>), ExecError> {
+        run_exec(self, command, timeout, prefix, capture_stdout).await
+    }
+
+    async fn write_file(
+        &self,
+        data: &[u8],
+        remote_path: &str,
+        timeout: &Duration,
+    ) -> Result<(), MainError> {
+        scp_send(self, data, remote_path, timeout).await
+    }
+}
+
+/// A non-blocking SSH session driven by tokio.
+///
+/// When `libssh2` would block, the task waits for the socket to become readable or writable (as
+/// given by [`ssh2::Session::block_directions`]) rather than spinning.
+#[derive(Clone)]
+struct AsyncSession {
+    session: ssh2::Session,
+    /// A duplicate of the socket owned by `session`, registered with the tokio reactor.
+    socket: Arc<tokio::io::unix::AsyncFd<std::os::fd::OwnedFd>>,
+}
+
+impl AsyncSession {
+    /// Creates a session over `stream`, which must be in non-blocking mode.
+    fn new<S: std::os::fd::AsFd + std::os::fd::AsRawFd + 'static>(
+        stream: S,
+    ) -> std::io::Result<Self> {
+        let socket = Arc::new(tokio::io::unix::AsyncFd::new(
+            stream.as_fd().try_clone_to_owned()?,
+        )?);
+        let mut session = ssh2::Session::new()?;
+        session.set_tcp_stream(stream);
+        session.set_blocking(false);
+        Ok(Self { session, socket })
+    }
+
+    fn session(&self) -> &ssh2::Session {
+        &self.session
+    }
+
+    /// Calls `op` until it doesn't fail with [`ErrorKind::WouldBlock`], waiting for the socket to
+    /// be ready between calls.
+    async fn run<T>(&self, mut op: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+        loop {
+            match op() {
+                Err(err) if err.kind() == WouldBlock => self.wait().await?,
+                result => return result,
+            }
+        }
+    }
+
+    /// Waits until the socket is ready in the direction the last operation blocked on.
+    ///
+    /// Readiness is cleared before returning, so it must be followed by retrying the operation.
+    async fn wait(&self) -> std::io::Result<()> {
+        let readable = async {
+            self.socket
+                .readable()
+                .await
+                .map(|mut guard| guard.clear_ready())
+        };
+        let writable = async {
+            self.socket
+                .writable()
+                .await
+                .map(|mut guard| guard.clear_ready())
+        };
+        match self.session.block_directions() {
+            ssh2::BlockDirections::Inbound => readable.await,
+            ssh2::BlockDirections::Outbound => writable.await,
+            ssh2::BlockDirections::Both => tokio::select! {
+                result = readable => result,
+                result = writable => result,
+            },
+            // Nothing to wait on, so retrying at once would spin.
+            ssh2::BlockDirections::None => {
+                tokio::time::sleep(SSH_RETRY_SLEEP).await;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Connects directly to SSH on the instance at `address`, waiting until it is ready.
+async fn connect_direct(address: &str, timeout: &Duration) -> Result<AsyncSession, MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    info!("Connecting SSH");
+    let ip_address = std::net::IpAddr::from_str(address).map_err(IpParse)?;
+    let socket_address = std::net::SocketAddr::new(ip_address, EC2_SSH_PORT);
+    info!("socket_address: {socket_address}");
+    let tcp = wait_for_ssh(&socket_address, timeout).await?;
+    tcp.set_nonblocking(true).map_err(SshSession)?;
+    AsyncSession::new(tcp).map_err(SshSession)
+}
+
+/// Connects to SSH on the instance at `address` through the jump host, waiting until it is
+/// ready.
+///
+/// The connection is forwarded through a `direct-tcpip` channel of a session with the jump host,
+/// by a task copying between the channel and one end of a socket pair, the other end of which is
+/// returned as the session with the instance.
+async fn connect_through(
+    jump_host: &JumpHost,
+    address: &str,
+    timeout: &Duration,
+    verify_host_key: bool,
+) -> Result<AsyncSession, MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    let start = Instant::now();
+    let jump = connect_jump_host(jump_host, timeout, verify_host_key).await?;
+
+    // Opening the channel fails until the instance accepts connections.
+    info!("Waiting for SSH to be ready through {jump_host}");
+    let mut backoff = SSH_READY_INITIAL_BACKOFF;
+    let channel = loop {
+        let open = jump.run(|| {
+            jump.session()
+                .channel_direct_tcpip(address, EC2_SSH_PORT, None)
+                .map_err(std::io::Error::from)
+        });
+        match tokio::time::timeout(timeout.saturating_sub(start.elapsed()), open).await {
+            Ok(Ok(channel)) => break channel,
+            Ok(Err(err)) if start.elapsed() + backoff <= *timeout => {
+                debug!("SSH not ready through jump host: {err}");
+            }
+            Ok(Err(err)) => return Err(JumpHostChannel(err)),
+            Err(_) => return Err(SshTimeout),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(SSH_READY_MAX_BACKOFF);
+    };
+
+    let (local, remote) = std::os::unix::net::UnixStream::pair().map_err(SshSession)?;
+    local.set_nonblocking(true).map_err(SshSession)?;
+    remote.set_nonblocking(true).map_err(SshSession)?;
+    let local = tokio::net::UnixStream::from_std(local).map_err(SshSession)?;
+    tokio::spawn(
+        async move {
+            if let Err(err) = forward(&jump, channel, &local).await {
+                error!("Forwarding through jump host failed: {err}");
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+    AsyncSession::new(remote).map_err(SshSession)
+}
+
+/// Connects and authenticates to the jump host.
+async fn connect_jump_host(
+    jump_host: &JumpHost,
+    timeout: &Duration,
+    verify_host_key: bool,
+) -> Result<AsyncSession, MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    info!("Connecting to jump host {jump_host}");
+    let deadline = Instant::now() + *timeout;
+    let name = jump_host.to_string();
+    let connect = tokio::net::TcpStream::connect((jump_host.host.as_str(), jump_host.port));
+    let tcp = tokio::time::timeout(remaining(deadline), connect)
+        .await
+        .map_err(|_| JumpHostTimeout(name.clone()))?
+        .and_then(tokio::net::TcpStream::into_std)
+        .map_err(|err| JumpHostConnect(name.clone(), err))?;
+    let jump = AsyncSession::new(tcp).map_err(SshSession)?;
+    handshake(&jump, &remaining(deadline)).await?;
+
+    if verify_host_key {
+        info!("Verifying jump host key");
+        verify_known_host(jump.session(), jump_host)?;
+    }
+
+    info!("Jump host authorize as {:?}", jump_host.user);
+    let authorize = jump.run(|| {
+        match &jump_host.identity {
+            Some(identity) => {
+                jump.session()
+                    .userauth_pubkey_file(&jump_host.user, None, identity, None)
+            }
+            None => jump.session().userauth_agent(&jump_host.user),
+        }
+        .map_err(std::io::Error::from)
+    });
+    tokio::time::timeout(remaining(deadline), authorize)
+        .await
+        .map_err(|_| JumpHostTimeout(name.clone()))?
+        .map_err(|err| JumpHostAuth(name.clone(), err))?;
+    if !jump.session().authenticated() {
+        let err = std::io::Error::other("not authenticated");
+        return Err(JumpHostAuth(name, err));
+    }
+    Ok(jump)
+}
+
+/// Verifies the host key of the jump host is in `~/.ssh/known_hosts`.
+fn verify_known_host(session: &ssh2::Session, jump_host: &JumpHost) -> Result<(), MainError> {
+    let key_error = || MainError::JumpHostKey(jump_host.to_string());
+    let mut known_hosts = session.known_hosts().map_err(|_| key_error())?;
+    if let Some(home) = std::env::var_os("HOME") {
+        let path = Path::new(&home).join(".ssh").join("known_hosts");
+        if path.exists() {
+            known_hosts
+                .read_file(&path, ssh2::KnownHostFileKind::OpenSSH)
+                .map_err(|_| key_error())?;
+        }
+    }
+    let (key, _) = session.host_key().ok_or_else(key_error)?;
+    match known_hosts.check_port(&jump_host.host, jump_host.port, key) {
+        ssh2::CheckResult::Match => Ok(()),
+        _ => Err(key_error()),
+    }
+}
+
+/// Copies data between `channel` and `local` until either is closed.
+async fn forward(
+    session: &AsyncSession,
+    mut channel: ssh2::Channel,
+    local: &tokio::net::UnixStream,
+) -> std::io::Result<()> {
+    let (mut to_channel, mut to_local) = (Vec::new(), Vec::new());
+    let (mut local_open, mut channel_open) = (true, true);
+    let mut buffer = vec![0; 16 * 1024];
+    loop {
+        // Whether any operation made progress, and whether the session blocked so waiting on its
+        // socket is correct.
+        let (mut progress, mut session_blocked) = (false, false);
+        if local_open && to_channel.is_empty() {
+            match local.try_read(&mut buffer) {
+                Ok(0) => (local_open, progress) = (false, true),
+                Ok(n) => (to_channel, progress) = (buffer[..n].to_vec(), true),
+                Err(err) if err.kind() == WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if !to_channel.is_empty() {
+            match channel.write(&to_channel) {
+                Ok(n) => (_, progress) = (to_channel.drain(..n), true),
+                Err(err) if err.kind() == WouldBlock => session_blocked = true,
+                Err(err) => return Err(err),
+            }
+        }
+        if channel_open && to_local.is_empty() {
+            match channel.read(&mut buffer) {
+                Ok(0) => (channel_open, progress) = (false, true),
+                Ok(n) => (to_local, progress) = (buffer[..n].to_vec(), true),
+                Err(err) if err.kind() == WouldBlock => session_blocked = true,
+                Err(err) => return Err(err),
+            }
+        }
+        if !to_local.is_empty() {
+            match local.try_write(&to_local) {
+                Ok(n) => (_, progress) = (to_local.drain(..n), true),
+                Err(err) if err.kind() == WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        // Either side closing ends the connection once what it sent is delivered.
+        if (!local_open && to_channel.is_empty()) || (!channel_open && to_local.is_empty()) {
+            return Ok(());
+        }
+        if !progress {
+            tokio::select! {
+                result = session.wait(), if session_blocked => result?,
+                result = local.readable(), if local_open && to_channel.is_empty() => result?,
+                result = local.writable(), if !to_local.is_empty() => result?,
+            }
+        }
+    }
+}
+
+/// Performs the SSH handshake.
+async fn handshake(ssh: &AsyncSession, timeout: &Duration) -> Result<(), MainError> {
+    info!("SSH handshake");
+    // `ssh2::Session` is a handle to a shared session, so the clone handshakes `ssh`.
+    let mut session = ssh.session().clone();
+    let handshake = ssh.run(|| session.handshake().map_err(std::io::Error::from));
+    tokio::time::timeout(*timeout, handshake)
+        .await
+        .map_err(|_| MainError::SshTimeout)?
+        .map_err(MainError::SshHandshake)
+}
+
+/// Authenticates the session with the instance as the first of `users` which succeeds.
+///
+/// If `host_keys` is given the host key presented by the server must be one of them.
+///
+/// Returns the session and the user it is authenticated as.
+async fn create_ssh(
+    ssh: AsyncSession,
+    timeout: &Duration,
+    private_key: &str,
+    users: &[String],
+    host_keys: Option<&HostKeys>,
+) -> Result<(AsyncSession, String), MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    let deadline = Instant::now() + *timeout;
+    handshake(&ssh, timeout).await?;
+
+    if let Some(host_keys) = host_keys {
+        info!("Verifying SSH host key");
+        host_keys.verify(ssh.session())?;
+    }
+
+    // SSH authorize
+    let authorize = async {
+        for user in users {
+            info!("SSH authorize as {user:?}");
+            let authenticated = ssh
+                .run(|| {
+                    match ssh
+                        .session()
+                        .userauth_pubkey_memory(user, None, private_key, None)
+                    {
+                        Ok(()) => Ok(true),
+                        Err(err)
+                            if matches!(
+                                err.code(),
+                                ssh2::ErrorCode::Session(
+                                    LIBSSH2_ERROR_AUTHENTICATION_FAILED
+                                        | LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED
+                                )
+                            ) =>
+                        {
+                            Ok(false)
+                        }
+                        Err(err) => Err(std::io::Error::from(err)),
+                    }
+                })
+                .await
+                .map_err(SshAuthSetup)?;
+
+            if authenticated && ssh.session().authenticated() {
+                return Ok(user.clone());
+            }
+            info!("SSH auth as {user:?} failed");
+        }
+        Err(SshAuthFailed(users.to_vec()))
+    };
+    let user = tokio::time::timeout(remaining(deadline), authorize)
+        .await
+        .map_err(|_| SshTimeout)??;
+    Ok((ssh, user))
+}
+
+/// The SSH host keys an instance prints to its console on first boot.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct HostKeys {
+    /// Fingerprints in the `SHA256:<base64>` form.
+    fingerprints: Vec<String>,
+    /// Public key blobs.
+    keys: Vec<Vec<u8>>,
+}
+
+impl HostKeys {
+    const FINGERPRINTS_BEGIN: &'static str = "-----BEGIN SSH HOST KEY FINGERPRINTS-----";
+    const FINGERPRINTS_END: &'static str = "-----END SSH HOST KEY FINGERPRINTS-----";
+    const KEYS_BEGIN: &'static str = "-----BEGIN SSH HOST KEY KEYS-----";
+    const KEYS_END: &'static str = "-----END SSH HOST KEY KEYS-----";
+
+    /// Parses the host keys from the console output.
+    ///
+    /// Returns `None` if the console output doesn't yet contain any host keys.
+    fn parse(console: &str) -> Option<Self> {
+        let section = |begin: &str, end: &str| {
+            let start = console.find(begin)? + begin.len();
+            let len = console[start..].find(end)?;
+            Some(console[start..start + len].lines())
+        };
+
+        let mut host_keys = Self::default();
+        if let Some(lines) = section(Self::FINGERPRINTS_BEGIN, Self::FINGERPRINTS_END) {
+            // e.g. `256 SHA256:<base64> root@ip-172-31-0-1 (ED25519)`
+            host_keys.fingerprints = lines
+                .flat_map(str::split_whitespace)
+                .filter(|word| word.starts_with("SHA256:"))
+                .map(String::from)
+                .collect();
+        }
+        if let Some(lines) = section(Self::KEYS_BEGIN, Self::KEYS_END) {
+            // e.g. `ssh-ed25519 <base64> root@ip-172-31-0-1`
+            host_keys.keys = lines
+                .filter_map(|line| line.split_whitespace().nth(1))
+                .filter_map(|key| aws_smithy_types::base64::decode(key).ok())
+                .collect();
+        }
+        (!host_keys.fingerprints.is_empty() || !host_keys.keys.is_empty()).then_some(host_keys)
+    }
+
+    /// Checks the host key presented in the session is one of these keys.
+    fn verify(&self, ssh: &ssh2::Session) -> Result<(), MainError> {
+        let fingerprint = ssh
+            .host_key_hash(ssh2::HashType::Sha256)
+            .map(|hash| {
+                format!(
+                    "SHA256:{}",
+                    aws_smithy_types::base64::encode(hash).trim_end_matches('=')
+                )
+            })
+            .unwrap_or_default();
+        let matches = ssh
+            .host_key()
+            .is_some_and(|(key, _)| self.keys.iter().any(|k| k == key))
+            || self.fingerprints.contains(&fingerprint);
+        if matches {
+            Ok(())
+        } else {
+            Err(MainError::HostKeyMismatch(fingerprint))
+        }
+    }
+}
+
+/// Polls the console output of the instance until it contains the SSH host keys.
+async fn get_host_keys(
+    client: &ec2::Client,
+    timeout: &Duration,
+    instance_id: &str,
+) -> Result<HostKeys, MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    let start = Instant::now();
+    info!("Waiting for SSH host keys in console output");
+    // The buffered output is only updated every few minutes, so the latest output is used where
+    // supported (on Nitro instances).
+    let mut latest = true;
+    loop {
+        let builder = client
+            .get_console_output()
+            .instance_id(instance_id)
+            .latest(latest);
+        match builder.send().await {
+            Ok(response) => {
+                if let Some(output) = response.output() {
+                    let output =
+                        aws_smithy_types::base64::decode(output).map_err(ConsoleOutputDecode)?;
+                    if let Some(host_keys) = HostKeys::parse(&String::from_utf8_lossy(&output)) {
+                        return Ok(host_keys);
+                    }
+                }
+                if start.elapsed() > *timeout {
+                    return Err(HostKeysTimeout);
+                }
+            }
+            Err(err) if latest && err.code() == Some("UnsupportedOperation") => {
+                info!("Latest console output unsupported, using buffered output: {err}");
+                latest = false;
+                continue;
+            }
+            // e.g. the instance isn't yet visible to `GetConsoleOutput`.
+            Err(err) if start.elapsed() <= *timeout => {
+                info!("Getting console output failed, retrying: {err}");
+            }
+            Err(err) => return Err(GetConsoleOutput(err)),
+        }
+        tokio::time::sleep(CONSOLE_OUTPUT_POLL_SLEEP).await;
+    }
+}
+
+/// Infers the users to try logging in as from the owner and name of the AMI.
+async fn infer_users(client: &ec2::Client, ami: &str) -> Result<Vec<String>, MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    info!("Describing image");
+    let builder = client.describe_images().image_ids(ami);
+    let describe_images_response = builder.send().await.map_err(DescribeImages)?;
+    let Some([image]) = describe_images_response.images.as_deref() else {
+        return Err(DescribeImagesImage(String::from(ami)));
+    };
+    let users = default_users(image.owner_id(), image.name());
+    info!("Inferred users {users:?}");
+    Ok(users.iter().copied().map(String::from).collect())
+}
+
+/// The architecture AMIs are resolved for on `instance_type`, `arm64` if it supports it else
+/// `x86_64`.
+async fn architecture(
+    client: &ec2::Client,
+    instance_type: &InstanceType,
+) -> Result<ec2::types::ArchitectureType, MainError> {
+    use ec2::types::ArchitectureType;
+
+    info!("Describing instance type");
+    let response = client
+        .describe_instance_types()
+        .instance_types(instance_type.clone())
+        .send()
+        .await
+        .map_err(MainError::DescribeInstanceTypes)?;
+    let architectures = response
+        .instance_types()
+        .and_then(|types| types.first())
+        .and_then(|info| info.processor_info())
+        .and_then(|info| info.supported_architectures())
+        .unwrap_or_default();
+    [ArchitectureType::Arm64, ArchitectureType::X8664]
+        .into_iter()
+        .find(|architecture| architectures.contains(architecture))
+        .ok_or_else(|| MainError::InstanceTypeArchitecture(String::from(instance_type.as_str())))
+}
+
+/// The id of the most recently created available image owned by `owner`, with a name matching
+/// `pattern`, for `architecture`.
+async fn latest_image(
+    client: &ec2::Client,
+    owner: &str,
+    pattern: &str,
+    architecture: &ec2::types::ArchitectureType,
+) -> Result<String, MainError> {
+    let filter = |name: &str, value: &str| {
+        ec2::types::Filter::builder()
+            .name(name)
+            .values(value)
+            .build()
+    };
+    info!("Describing images owned by {owner:?} named {pattern:?}");
+    let response = client
+        .describe_images()
+        .owners(owner)
+        .filters(filter("name", pattern))
+        .filters(filter("architecture", architecture.as_str()))
+        .filters(filter("state", "available"))
+        .send()
+        .await
+        .map_err(MainError::DescribeImages)?;
+    // Creation dates are ISO 8601 timestamps, so order lexicographically.
+    response
+        .images()
+        .unwrap_or_default()
+        .iter()
+        .filter(|image| image.image_id().is_some())
+        .max_by_key(|image| image.creation_date().unwrap_or_default())
+        .and_then(|image| image.image_id())
+        .map(String::from)
+        .ok_or_else(|| {
+            MainError::AmiNameNotFound(
+                String::from(owner),
+                String::from(pattern),
+                String::from(architecture.as_str()),
+            )
+        })
+}
+
+/// The instance types of `instance_types` which are offered in the region.
+async fn instance_type_offerings(
+    client: &ec2::Client,
+    instance_types: &[InstanceType],
+) -> Result<Vec<InstanceType>, MainError> {
+    info!("Describing instance type offerings");
+    let response = client
+        .describe_instance_type_offerings()
+        .location_type(ec2::types::LocationType::Region)
+        .filters(
+            ec2::types::Filter::builder()
+                .name("instance-type")
+                .set_values(Some(
+                    instance_types
+                        .iter()
+                        .map(|instance_type| String::from(instance_type.as_str()))
+                        .collect(),
+                ))
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(MainError::DescribeInstanceTypeOfferings)?;
+    Ok(response
+        .instance_type_offerings()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|offering| offering.instance_type().cloned())
+        .collect())
+}
+
+/// Describes `instance_types`, which must all exist.
+async fn describe_instance_types(
+    client: &ec2::Client,
+    instance_types: &[InstanceType],
+) -> Result<Vec<ec2::types::InstanceTypeInfo>, MainError> {
+    if instance_types.is_empty() {
+        return Ok(Vec::new());
+    }
+    info!("Describing instance types");
+    let response = client
+        .describe_instance_types()
+        .set_instance_types(Some(instance_types.to_vec()))
+        .send()
+        .await
+        .map_err(MainError::DescribeInstanceTypes)?;
+    Ok(response.instance_types.unwrap_or_default())
+}
+
+/// Describes the images of `amis` which exist, filtering rather than listing the ids so missing
+/// ones don't fail the request.
+async fn describe_images(
+    client: &ec2::Client,
+    amis: &[String],
+) -> Result<Vec<ec2::types::Image>, MainError> {
+    info!("Describing images");
+    let response = client
+        .describe_images()
+        .filters(
+            ec2::types::Filter::builder()
+                .name("image-id")
+                .set_values(Some(amis.to_vec()))
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(MainError::DescribeImages)?;
+    Ok(response.images.unwrap_or_default())
+}
+
+/// The reasons `instance_type` can't boot `ami`, where `info` is `None` if the instance type isn't
+/// offered in `region` and `image` is `None` if the AMI wasn't found.
+fn incompatibilities(
+    instance_type: &InstanceType,
+    region: &str,
+    info: Option<&ec2::types::InstanceTypeInfo>,
+    ami: &str,
+    image: Option<&ec2::types::Image>,
+) -> Vec<String> {
+    use ec2::types::{BootModeType, BootModeValues, EbsNvmeSupport, EnaSupport, ImageState};
+
+    let instance_type = instance_type.as_str();
+    let mut mismatches = Vec::new();
+    let Some(info) = info else {
+        mismatches.push(format!(
+            "instance type {instance_type} is not offered in {region}"
+        ));
+        return mismatches;
+    };
+    let Some(image) = image else {
+        mismatches.push(format!("AMI {ami} not found in {region}"));
+        return mismatches;
+    };
+    if let Some(state) = image
+        .state()
+        .filter(|state| **state != ImageState::Available)
+    {
+        mismatches.push(format!("AMI {ami} is {}", state.as_str()));
+    }
+
+    let architectures = info
+        .processor_info()
+        .and_then(|info| info.supported_architectures())
+        .unwrap_or_default();
+    if let Some(architecture) = image.architecture().filter(|architecture| {
+        !architectures
+            .iter()
+            .any(|supported| supported.as_str() == architecture.as_str())
+    }) {
+        mismatches.push(format!(
+            "AMI {ami} is {} but {instance_type} supports {}",
+            architecture.as_str(),
+            join_values(
+                architectures
+                    .iter()
+                    .map(ec2::types::ArchitectureType::as_str)
+            )
+        ));
+    }
+
+    let virtualization_types = info.supported_virtualization_types().unwrap_or_default();
+    if let Some(virtualization_type) = image
+        .virtualization_type()
+        .filter(|virtualization_type| !virtualization_types.contains(virtualization_type))
+    {
+        mismatches.push(format!(
+            "AMI {ami} uses {} virtualization but {instance_type} supports {}",
+            virtualization_type.as_str(),
+            join_values(
+                virtualization_types
+                    .iter()
+                    .map(ec2::types::VirtualizationType::as_str)
+            )
+        ));
+    }
+
+    // Images without a boot mode use the default of the instance type, and `uefi-preferred` ones
+    // boot either way.
+    let boot_modes = info.supported_boot_modes().unwrap_or_default();
+    let boot_mode = match image.boot_mode() {
+        Some(BootModeValues::LegacyBios) => Some(BootModeType::LegacyBios),
+        Some(BootModeValues::Uefi) => Some(BootModeType::Uefi),
+        _ => None,
+    };
+    if let Some(boot_mode) = boot_mode.filter(|boot_mode| !boot_modes.contains(boot_mode)) {
+        mismatches.push(format!(
+            "AMI {ami} boots with {} but {instance_type} supports {}",
+            boot_mode.as_str(),
+            join_values(boot_modes.iter().map(BootModeType::as_str))
+        ));
+    }
+
+    // Images can't declare NVMe drivers, but those without ENA support predate the Nitro
+    // instance types which require both.
+    let mut requires = Vec::new();
+    if info.network_info().and_then(|info| info.ena_support()) == Some(&EnaSupport::Required) {
+        requires.push("ENA");
+    }
+    if info.ebs_info().and_then(|info| info.nvme_support()) == Some(&EbsNvmeSupport::Required) {
+        requires.push("NVMe");
+    }
+    if !requires.is_empty() && image.ena_support() != Some(true) {
+        mismatches.push(format!(
+            "{instance_type} requires {} drivers but AMI {ami} doesn't support ENA",
+            requires.join(" and ")
+        ));
+    }
+    mismatches
+}
+
+/// Joins values for a message, `none` if there are none.
+fn join_values<'a>(values: impl Iterator<Item = &'a str>) -> String {
+    let values = values.collect::<Vec<_>>();
+    if values.is_empty() {
+        String::from("none")
+    } else {
+        values.join(", ")
+    }
+}
+
+/// The default login users for an AMI with the given owner and name.
+///
+/// When the distribution cannot be identified the most common users are returned.
+fn default_users(owner_id: Option<&str>, name: Option<&str>) -> &'static [&'static str] {
+    let name = name.unwrap_or_default().to_lowercase();
+    match owner_id {
+        Some(CANONICAL_OWNER_ID) => return &["ubuntu"],
+        Some(DEBIAN_OWNER_ID) => return &["admin"],
+        Some(RED_HAT_OWNER_ID) => return &["ec2-user"],
+        Some(FEDORA_OWNER_ID) => return &["fedora"],
+        _ => {}
+    }
+    if name.contains("ubuntu") {
+        &["ubuntu"]
+    } else if name.starts_with("debian") {
+        &["admin"]
+    } else if name.starts_with("fedora") {
+        &["fedora"]
+    } else if name.starts_with("centos") {
+        &["centos", "ec2-user"]
+    } else if [
+        "al2023-ami",
+        "amzn",
+        "bottlerocket",
+        "rhel",
+        "suse",
+        "freebsd",
+    ]
+    .iter()
+    .any(|prefix| name.starts_with(prefix))
+    {
+        &["ec2-user"]
+    } else {
+        &["ubuntu", "ec2-user", "admin"]
+    }
+}
+
+/// Formats the steps run on each target as a table.
+fn summary_table(reports: &[(String, Market, Vec<StepReport>)]) -> String {
+    let mut rows = vec![[
+        String::from("TARGET"),
+        String::from("MARKET"),
+        String::from("STEP"),
+        String::from("STATUS"),
+        String::from("STARTED"),
+        String::from("DURATION"),
+    ]];
+    for (label, market, steps) in reports {
+        for step in steps {
+            rows.push([
+                label.clone(),
+                market.to_string(),
+                step.name.clone(),
+                step.status.to_string(),
+                step.started.map_or_else(
+                    || String::from("-"),
+                    |started| {
+                        ec2::primitives::DateTime::from(started)
+                            .fmt(ec2::primitives::DateTimeFormat::DateTime)
+                            .unwrap()
+                    },
+                ),
+                step.duration
+                    .map_or_else(|| String::from("-"), |duration| format!("{duration:.1?}")),
+            ]);
+        }
+    }
+    let mut widths = [0; 6];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The path the private key is written to when lingering.
+fn linger_key_path(key_name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("{key_name}.pem"))
+}
+
+/// Writes the private key to `path` readable only by the current user, as required by `ssh`.
+fn write_private_key(path: &Path, private_key: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(private_key.as_bytes())
+}
+
+/// Waits until an SSH server at `socket_address` accepts connections and sends its banner.
+///
+/// An instance in the `running` state may still be booting, so both connecting and waiting for the
+/// banner are retried with exponential backoff until `timeout`. The banner is peeked rather than
+/// read so the returned stream can be handed to `ssh2` for the handshake.
+async fn wait_for_ssh(
+    socket_address: &std::net::SocketAddr,
+    timeout: &Duration,
+) -> Result<std::net::TcpStream, MainError> {
+    let start = Instant::now();
+    let mut backoff = SSH_READY_INITIAL_BACKOFF;
+    info!("Waiting for SSH to be ready");
+    loop {
+        let remaining = timeout.saturating_sub(start.elapsed());
+        let attempt = peek_ssh_banner(socket_address, remaining.min(SSH_READY_ATTEMPT_TIMEOUT));
+        let err = match attempt.await {
+            Ok(tcp) => return Ok(tcp),
+            Err(err) => err,
+        };
+        if start.elapsed() + backoff > *timeout {
+            return Err(MainError::SshReadyTimeout(err));
+        }
+        info!("SSH not ready ({err}), retrying in {backoff:?}");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(SSH_READY_MAX_BACKOFF);
+    }
+}
+
+/// Connects to `socket_address` and waits for the server to send data, without consuming it.
+async fn peek_ssh_banner(
+    socket_address: &std::net::SocketAddr,
+    timeout: Duration,
+) -> std::io::Result<std::net::TcpStream> {
+    let peek = async {
+        let tcp = tokio::net::TcpStream::connect(socket_address).await?;
+        if tcp.peek(&mut [0]).await? == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before SSH banner",
+            ));
+        }
+        tcp.into_std()
+    };
+    tokio::time::timeout(timeout, peek)
+        .await
+        .map_err(|_| std::io::Error::from(ErrorKind::TimedOut))?
+}
+
+/// Waits until the given instance is in the running state.
+async fn wait_until_running(
+    client: &ec2::Client,
+    timeout: &Duration,
+    instance_id: &str,
+) -> Result<(), MainError> {
+    info!("Waiting for instance to enter the `running` state");
+    wait_until_state(
+        client,
+        timeout,
+        instance_id,
+        &ec2::types::InstanceStateName::Running,
+    )
+    .await?
+    .ok_or(MainError::LaunchTimeout)
+}
+
+/// Waits until the given instance is in the terminated state.
+async fn wait_until_terminated(
+    client: &ec2::Client,
+    timeout: &Duration,
+    instance_id: &str,
+) -> Result<(), MainError> {
+    info!("Waiting for instance to enter the `terminated` state");
+    wait_until_state(
+        client,
+        timeout,
+        instance_id,
+        &ec2::types::InstanceStateName::Terminated,
+    )
+    .await?
+    .ok_or(MainError::TerminateTimeout)
+}
+
+/// Polls the state of the given instance until it is `target`.
+///
+/// Returns `None` on timeout.
+async fn wait_until_state(
+    client: &ec2::Client,
+    timeout: &Duration,
+    instance_id: &str,
+    target: &ec2::types::InstanceStateName,
+) -> Result<Option<()>, MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    let start = Instant::now();
+    loop {
+        if start.elapsed() > *timeout {
+            return Ok(None);
+        }
+
+        tokio::time::sleep(INSTANCE_POLL_STATE_SLEEP).await;
+
+        let builder = client
+            .describe_instance_status()
+            .set_instance_ids(Some(vec![instance_id.to_string()]))
+            // By default this doesn't return descriptions for instances outside the `running`
+            // state, in our case we want these description, so we set this parameter.
+            .set_include_all_instances(Some(true));
+        let describe_instance_status_response =
+            builder.send().await.map_err(DescribeInstanceStatus)?;
+
+        let Some(
+            [ec2::types::InstanceStatus {
+                instance_state:
+                    Some(ec2::types::InstanceState {
+                        name: Some(state), ..
+                    }),
+                ..
+            }],
+        ) = describe_instance_status_response
+            .instance_statuses
+            .as_deref()
+        else {
+            return Err(DescribeInstanceStatusState);
+        };
+        if state == target {
+            return Ok(Some(()));
+        }
+    }
+}
+
+/// Terminates the given instances, waiting until they are terminated.
+async fn terminate_instances(
+    client: &ec2::Client,
+    timeout: &Duration,
+    instance_ids: &[String],
+) -> Result<(), MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    info!("Terminate instances");
+    let builder = client
+        .terminate_instances()
+        .set_instance_ids(Some(instance_ids.to_vec()));
+    builder.send().await.map_err(TerminateInstances)?;
+
+    // The security group cannot be deleted until the instances are terminated.
+    for instance_id in instance_ids {
+        wait_until_terminated(client, timeout, instance_id).await?;
+    }
+    Ok(())
+}
+
+/// Deletes the given key pair, and its private key if it was written when lingering.
+async fn delete_key_pair(client: &ec2::Client, key_name: &str) -> Result<(), MainError> {
+    info!("Deleting key pair");
+    let builder = client
+        .delete_key_pair()
+        .set_key_name(Some(String::from(key_name)));
+    builder.send().await.map_err(MainError::DeleteKeyPair)?;
+
+    // The private key is useless once the key pair is deleted.
+    let key_path = linger_key_path(key_name);
+    match std::fs::remove_file(&key_path) {
+        Ok(()) => info!("Deleted private key {}", key_path.display()),
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(MainError::DeletePrivateKey(err)),
+    }
+    Ok(())
+}
+
+/// Deletes the given security group.
+///
+/// Retries while the security group is still in use by the network interfaces of recently
+/// terminated instances.
+async fn delete_security_group(
+    client: &ec2::Client,
+    timeout: &Duration,
+    security_group_id: &str,
+) -> Result<(), MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    let start = Instant::now();
+    info!("Deleting security group");
+    loop {
+        let builder = client
+            .delete_security_group()
+            .set_group_id(Some(String::from(security_group_id)));
+        match builder.send().await {
+            Ok(_) => return Ok(()),
+            Err(err) => match delete_retry(err.code(), start.elapsed(), timeout) {
+                DeleteRetry::Retry => {
+                    info!("Security group still in use, sleeping for {DELETE_SECURITY_GROUP_RETRY_SLEEP:?}.");
+                    tokio::time::sleep(DELETE_SECURITY_GROUP_RETRY_SLEEP).await;
+                }
+                DeleteRetry::TimedOut => return Err(DeleteSecurityGroupTimeout),
+                DeleteRetry::Failed => return Err(DeleteSecurityGroup(err)),
+            },
+        }
+    }
+}
+
+/// What to do after deleting a security group fails.
+#[derive(Debug, PartialEq, Eq)]
+enum DeleteRetry {
+    Retry,
+    TimedOut,
+    Failed,
+}
+
+/// Decides whether to retry deleting a security group which failed with the error `code`,
+/// `elapsed` after the first attempt.
+///
+/// Only a security group still in use by the network interfaces of recently terminated instances
+/// is retried, for up to `timeout`.
+fn delete_retry(code: Option<&str>, elapsed: Duration, timeout: &Duration) -> DeleteRetry {
+    match code {
+        Some("DependencyViolation") if elapsed > *timeout => DeleteRetry::TimedOut,
+        Some("DependencyViolation") => DeleteRetry::Retry,
+        _ => DeleteRetry::Failed,
+    }
+}
+
+/// Using the default as recommend here
+/// <https://docs.rs/aws-sdk-ec2/0.33.0/aws_sdk_ec2/types/builders/struct.BlockDeviceMappingBuilder.html#method.set_device_name>
+/// and here <https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/device_naming.html>.
+const DEFAULT_BLOCK_DEVICE_NAME: &str = "/dev/sdh";
+
+/// Launches an EC2 instance and returns its ip addresses and id.
+#[allow(clippy::too_many_arguments)]
+async fn launch_instance(
+    client: &ec2::Client,
+    cleanup: &Cleanup,
+    run_id: &str,
+    instance_type: &InstanceType,
+    ami: &str,
+    key_name: &str,
+    security_group_id: &str,
+    timeout: &Duration,
+    size: &VolumeSize,
+    spot: Option<&Spot>,
+    zone: Option<&str>,
+    subnet_id: Option<&str>,
+    placement_group: Option<&str>,
+    public_ip: bool,
+    instance_profile: Option<&str>,
+) -> Result<(Addresses, String), MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    info!("Launching instances");
+    let market_options = spot.map(spot_market_options);
+    let placement = (zone.is_some() || placement_group.is_some()).then(|| {
+        ec2::types::Placement::builder()
+            .set_availability_zone(zone.map(String::from))
+            .set_group_name(placement_group.map(String::from))
+            .build()
+    });
+    // Subnets outside the default VPC often don't assign public IP addresses by default, so whether
+    // to is set on the network interface, which then holds the security group.
+    let (security_group_ids, network_interfaces) = match subnet_id {
+        Some(subnet_id) => (
+            None,
+            Some(vec![
+                ec2::types::InstanceNetworkInterfaceSpecification::builder()
+                    .device_index(0)
+                    .subnet_id(subnet_id)
+                    .groups(security_group_id)
+                    .associate_public_ip_address(public_ip)
+                    .delete_on_termination(true)
+                    .build(),
+            ]),
+        ),
+        None => (Some(vec![String::from(security_group_id)]), None),
+    };
+    let builder = client
+        .run_instances()
+        .set_instance_type(Some(instance_type.clone()))
+        .set_image_id(Some(String::from(ami)))
+        .set_max_count(Some(1))
+        .set_min_count(Some(1))
+        .set_key_name(Some(String::from(key_name)))
+        .set_security_group_ids(security_group_ids)
+        .set_network_interfaces(network_interfaces)
+        .set_instance_market_options(market_options)
+        .set_placement(placement)
+        .set_iam_instance_profile(instance_profile.map(|name| {
+            ec2::types::IamInstanceProfileSpecification::builder()
+                .name(name)
+                .build()
+        }))
+        .tag_specifications(tag_specification(
+            ec2::types::ResourceType::Instance,
+            run_id,
+        ))
+        .tag_specifications(tag_specification(ec2::types::ResourceType::Volume, run_id))
+        .set_block_device_mappings(Some(vec![aws_sdk_ec2::types::BlockDeviceMapping::builder(
+        )
+        .ebs(
+            aws_sdk_ec2::types::EbsBlockDevice::builder()
+                .set_volume_size(Some(i32::from(*size)))
+                .build(),
+        )
+        .set_device_name(Some(String::from(DEFAULT_BLOCK_DEVICE_NAME)))
+        .build()]));
+    let run_instances_response = builder.send().await.map_err(RunInstances)?;
+
+    let Some(
+        [aws_sdk_ec2::types::Instance {
+            instance_id: Some(instance_id),
+            ..
+        }],
+    ) = &run_instances_response.instances.as_deref()
+    else {
+        return Err(RunInstancesInstanceId);
+    };
+    cleanup.push(Resource::Instance(instance_id.clone()));
+
+    // The instance is not immediately assigned a public IP address so we need to wait.
+    wait_until_running(client, timeout, instance_id.as_str()).await?;
+
+    info!("Getting running instance description");
+    let builder = client
+        .describe_instances()
+        .set_instance_ids(Some(vec![instance_id.clone()]));
+    let describe_instances_response = builder.send().await.map_err(DescribeInstances)?;
+    let description = match describe_instances_response.reservations.as_deref() {
+        Some(
+            [ec2::types::Reservation {
+                instances: Some(instance_descriptions),
+                ..
+            }],
+        ) if let [description] = instance_descriptions.as_slice() => description,
+        _ => return Err(DescribeInstancesInstance),
+    };
+    let addresses = Addresses {
+        public: description.public_ip_address.clone(),
+        private: description.private_ip_address.clone(),
+    };
+
+    Ok((addresses, instance_id.clone()))
+}
+
+/// The options to launch a one-time spot instance, terminated on interruption.
+fn spot_market_options(spot: &Spot) -> ec2::types::InstanceMarketOptionsRequest {
+    ec2::types::InstanceMarketOptionsRequest::builder()
+        .market_type(ec2::types::MarketType::Spot)
+        .spot_options(
+            ec2::types::SpotMarketOptions::builder()
+                .set_max_price(spot.max_price.clone())
+                .spot_instance_type(ec2::types::SpotInstanceType::OneTime)
+                .instance_interruption_behavior(ec2::types::InstanceInterruptionBehavior::Terminate)
+                .build(),
+        )
+        .build()
+}
+
+/// The IP addresses of an instance.
+#[derive(Debug, Clone)]
+struct Addresses {
+    public: Option<String>,
+    private: Option<String>,
+}
+
+impl Addresses {
+    /// The address to connect to.
+    fn connect(&self, connect: Connect) -> Result<&str, MainError> {
+        match connect {
+            Connect::Public => self
+                .public
+                .as_deref()
+                .ok_or(MainError::DescribeInstancesPublicIpAddress),
+            Connect::Private => self
+                .private
+                .as_deref()
+                .ok_or(MainError::DescribeInstancesPrivateIpAddress),
+            Connect::Auto => self
+                .public
+                .as_deref()
+                .or(self.private.as_deref())
+                .ok_or(MainError::DescribeInstancesPrivateIpAddress),
+        }
+    }
+}
+
+/// Whether launching a spot instance failed as there is no spot capacity at the max price.
+fn is_spot_capacity_error(err: &MainError) -> bool {
+    matches!(
+        err,
+        MainError::RunInstances(err) if err.code().is_some_and(|code| SPOT_CAPACITY_ERROR_CODES.contains(&code))
+    )
+}
+
+/// Whether to try launching a spot instance in each availability zone, which isn't possible when
+/// a subnet or zone given fixes the zone.
+fn tries_other_zones(spot: &Spot, network: &Network) -> bool {
+    let zone_fixed = network.subnet_id.is_some() || network.availability_zone.is_some();
+    spot.other_zones && !zone_fixed
+}
+
+/// Whether to run a target on an on-demand instance after running it on a spot instance failed
+/// with `err`, as there was no spot capacity or the spot instance was interrupted.
+fn on_demand_fallback(spot: &Spot, err: &MainError) -> bool {
+    spot.on_demand_fallback
+        && (is_spot_capacity_error(err) || matches!(err, MainError::SpotInterrupted(..)))
+}
+
+/// The filters matching the available subnets of `vpc_id` in the zone, `zone` overriding
+/// [`Network::availability_zone`], with every one of [`Network::subnet_tags`].
+fn subnet_filters(network: &Network, vpc_id: &str, zone: Option<&str>) -> Vec<ec2::types::Filter> {
+    let mut filters = vec![
+        ec2::types::Filter::builder()
+            .name("vpc-id")
+            .values(vpc_id)
+            .build(),
+        ec2::types::Filter::builder()
+            .name("state")
+            .values("available")
+            .build(),
+    ];
+    if let Some(zone) = zone.or(network.availability_zone.as_deref()) {
+        filters.push(
+            ec2::types::Filter::builder()
+                .name("availability-zone")
+                .values(zone)
+                .build(),
+        );
+    }
+    for (key, value) in &network.subnet_tags {
+        filters.push(
+            ec2::types::Filter::builder()
+                .name(format!("tag:{key}"))
+                .values(value)
+                .build(),
+        );
+    }
+    filters
+}
+
+/// The availability zones of `subnets`, in order.
+fn subnet_zones(subnets: &[ec2::types::Subnet]) -> Vec<String> {
+    let mut zones = subnets
+        .iter()
+        .filter_map(|subnet| subnet.availability_zone().map(String::from))
+        .collect::<Vec<_>>();
+    zones.sort();
+    zones.dedup();
+    zones
+}
+
+/// The id of the subnet with the most free addresses, then the lowest id so it is stable.
+fn select_subnet(subnets: &[ec2::types::Subnet]) -> Option<String> {
+    subnets
+        .iter()
+        .filter_map(|subnet| {
+            let id = subnet.subnet_id()?;
+            Some((subnet.available_ip_address_count.unwrap_or_default(), id))
+        })
+        .min_by(|(a_count, a_id), (b_count, b_id)| {
+            b_count.cmp(a_count).then_with(|| a_id.cmp(b_id))
+        })
+        .map(|(_, id)| String::from(id))
+}
+
+/// Describes the subnets matching every filter.
+async fn describe_subnets(
+    client: &ec2::Client,
+    filters: Vec<ec2::types::Filter>,
+) -> Result<Vec<ec2::types::Subnet>, MainError> {
+    let response = client
+        .describe_subnets()
+        .set_filters(Some(filters))
+        .send()
+        .await
+        .map_err(MainError::DescribeSubnets)?;
+    Ok(response.subnets.unwrap_or_default())
+}
+
+/// The names of the available availability zones in the region.
+async fn availability_zones(client: &ec2::Client) -> Result<Vec<String>, MainError> {
+    let response = client
+        .describe_availability_zones()
+        .filters(
+            ec2::types::Filter::builder()
+                .name("state")
+                .values("available")
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(MainError::DescribeAvailabilityZones)?;
+    Ok(response
+        .availability_zones()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|zone| zone.zone_name().map(String::from))
+        .collect())
+}
+
+/// Polls the spot request of the instance until it is marked for interruption, returning its
+/// status code.
+///
+/// Errors describing the spot request are logged and polling continues, as they shouldn't stop
+/// the run.
+async fn wait_for_spot_interruption(client: &ec2::Client, instance_id: &str) -> String {
+    loop {
+        tokio::time::sleep(SPOT_INTERRUPTION_POLL_SLEEP).await;
+        let response = client
+            .describe_spot_instance_requests()
+            .filters(
+                ec2::types::Filter::builder()
+                    .name("instance-id")
+                    .values(instance_id)
+                    .build(),
+            )
+            .send()
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                error!("Failed to describe spot instance requests: {err}");
+                continue;
+            }
+        };
+        let interruption = response
+            .spot_instance_requests()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|request| request.status()?.code())
+            .find(|code| {
+                SPOT_INTERRUPTION_STATUS_PREFIXES
+                    .iter()
+                    .any(|prefix| code.starts_with(prefix))
+            });
+        if let Some(code) = interruption {
+            return String::from(code);
+        }
+    }
+}
+
+/// Compresses the source directory into a `.tar.gz` archive.
+///
+/// Files ignored by `.gitignore`, `.ignore` and `.awsec2ignore` files (including nested files and
+/// negations) are skipped, as are files not matching `source.include` or matching
+/// `source.exclude`.
+fn archive(source: &Source) -> Result<Vec<u8>, MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    let dir = Path::new(&source.path);
+    // Includes are matched separately rather than added to the walker's overrides, as
+    // whitelisted overrides would take precedence over the ignore files.
+    let mut include = ignore::overrides::OverrideBuilder::new(dir);
+    for glob in &source.include {
+        include.add(glob).map_err(ArchiveGlob)?;
+    }
+    let include = include.build().map_err(ArchiveGlob)?;
+    let mut overrides = ignore::overrides::OverrideBuilder::new(dir);
+    for glob in &source.exclude {
+        overrides.add(&format!("!{glob}")).map_err(ArchiveGlob)?;
+    }
+    let walker = ignore::WalkBuilder::new(dir)
+        .hidden(false)
+        .require_git(false)
+        .add_custom_ignore_filename(CUSTOM_IGNORE_FILENAME)
+        .overrides(overrides.build().map_err(ArchiveGlob)?)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build();
+
+    let mut tar_gz = Vec::new();
+    let enc = GzEncoder::new(&mut tar_gz, Compression::default());
+    let mut tar = tar::Builder::new(enc);
+    let (mut files, mut bytes) = (0, 0);
+    info!("Reading local directory: {dir:?}");
+    for entry in walker {
+        let entry = entry.map_err(ReadDir)?;
+        let name = entry.path().strip_prefix(dir).unwrap();
+        if name.as_os_str().is_empty() {
+            continue;
+        }
+        let Some(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            // With includes, directories are only created as parents of included files.
+            if include.is_empty() {
+                tar.append_dir(name, entry.path()).map_err(AppendDir)?;
+            }
+        } else if file_type.is_file()
+            && (include.is_empty() || include.matched(entry.path(), false).is_whitelist())
+        {
+            debug!("Packing: {name:?}");
+            tar.append_path_with_name(entry.path(), name)
+                .map_err(AppendFile)?;
+            files += 1;
+            bytes += entry.metadata().map_or(0, |metadata| metadata.len());
+        }
+    }
+    tar.into_inner().map_err(CompleteArchive)?;
+
+    info!(
+        "Packed {files} files ({bytes} bytes) into a {} byte archive",
+        tar_gz.len()
+    );
+    Ok(tar_gz)
+}
+
+/// Transfers the source archive to the instance as `remote_path`, decompresses it into
+/// `remote_dir` and removes it.
+async fn transfer_source(
+    data: &[u8],
+    remote_path: &str,
+    remote_dir: &str,
+    transport: &dyn Transport,
+    timeout: &Duration,
+    prefix: &str,
+) -> Result<(), MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    info!("Copying source");
+    // The timeout covers the transfer as a whole, so decompressing is given the time remaining.
+    let deadline = Instant::now() + *timeout;
+    transport.write_file(data, remote_path, timeout).await?;
+
+    info!("Decompressing source");
+
+    let (remote_path, remote_dir) = (shell_quote(remote_path), shell_quote(remote_dir));
+    let decompress = format!(
+        "mkdir -p {remote_dir} && tar -xf {remote_path} -C {remote_dir}; \
+        code=$?; rm -f {remote_path}; exit $code"
+    );
+    let Some(code) = transport
+        .exec(&decompress, &remaining(deadline), prefix, false)
+        .await
+        .map_err(Exec)?
+        .0
+    else {
+        return Err(TransferTimeout);
+    };
+    if code != 0 {
+        return Err(DecompressFailed(code));
+    }
+    Ok(())
+}
+
+/// Copies `data` to the instance as `remote_path` with scp.
+async fn scp_send(
+    ssh: &AsyncSession,
+    data: &[u8],
+    remote_path: &str,
+    timeout: &Duration,
+) -> Result<(), MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    // TODO What is the mode value of `0o644` doing here? I just copied it from the docs
+    // https://docs.rs/ssh2/latest/ssh2/#upload-a-file.
+    info!("scp send");
+    let deadline = Instant::now() + *timeout;
+    let send = async {
+        let mut channel = ssh
+            .run(|| {
+                ssh.session()
+                    .scp_send(Path::new(remote_path), 0o644, data.len() as u64, None)
+                    .map_err(std::io::Error::from)
+            })
+            .await
+            .map_err(ScpSend)?;
+
+        // Write archive to remote.
+        let mut n = 0;
+        while n < data.len() {
+            n += ssh
+                .run(|| channel.write(&data[n..]))
+                .await
+                .map_err(ScpWrite)?;
+        }
+        Ok(channel)
+    };
+    let mut channel = tokio::time::timeout(remaining(deadline), send)
+        .await
+        .map_err(|_| TransferTimeout)??;
+
+    // Wait send ending for write of archive to remote.
+    info!("Wait for scp end of file");
+    let finish = async {
+        ssh.run(|| channel.send_eof().map_err(std::io::Error::from))
+            .await
+            .map_err(ScpSendEof)?;
+        ssh.run(|| channel.wait_eof().map_err(std::io::Error::from))
+            .await
+            .map_err(ScpWaitEof)?;
+        info!("Closing scp");
+        ssh.run(|| channel.close().map_err(std::io::Error::from))
+            .await
+            .map_err(ScpClose)?;
+        info!("Waiting close scp");
+        ssh.run(|| channel.wait_close().map_err(std::io::Error::from))
+            .await
+            .map_err(ScpWaitClose)
+    };
+    tokio::time::timeout(remaining(deadline), finish)
+        .await
+        .map_err(|_| TransferTimeout)?
+}
+
+/// Archives the files matching `artifact.glob` in `remote_dir` (or the home directory) on the
+/// instance and unpacks them into `artifact.local_dir`.
+async fn download_artifact(
+    artifact: &Artifact,
+    remote_dir: Option<&str>,
+    transport: &dyn Transport,
+    timeout: &Duration,
+    prefix: &str,
+) -> Result<(), MainError> {
+    #[allow(clippy::enum_glob_use)]
+    use MainError::*;
+
+    info!(
+        "Downloading artifacts {:?} to {}",
+        artifact.glob,
+        artifact.local_dir.display()
+    );
+
+    // The glob is left unquoted so it is expanded by the remote shell, patterns matching nothing
+    // are skipped so they don't fail `tar`.
+    let cd = remote_dir.map_or_else(String::new, |dir| {
+        format!("cd {} || exit 1\n", shell_quote(dir))
+    });
+    let archive = format!(
+        "{cd}for f in {}; do [ -e \"$f\" ] && printf '%s\\0' \"$f\"; done | tar -czf - --null -T -",
+        artifact.glob
+    );
+    let (code, data) = transport
+        .exec(&archive, timeout, prefix, true)
+        .await
+        .map_err(ArtifactExec)?;
+    match code {
+        Some(0) => {}
+        Some(code) => return Err(ArtifactFailed(artifact.glob.clone(), code)),
+        None => return Err(TransferTimeout),
+    }
+
+    std::fs::create_dir_all(&artifact.local_dir).map_err(ArtifactUnpack)?;
+    tar::Archive::new(flate2::read::GzDecoder::new(data.as_slice()))
+        .unpack(&artifact.local_dir)
+        .map_err(ArtifactUnpack)?;
+    Ok(())
+}
+
+/// The time left until `deadline`, zero once it has passed.
+fn remaining(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
+
+/// Quotes `s` so it is interpreted literally by the remote shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Runs `command` over SSH, killing its process group if it times out.
+async fn run_exec(
+    session: &AsyncSession,
+    command: &str,
+    timeout: &Duration,
+    prefix: &str,
+    capture_stdout: bool,
+) -> Result<(Option<i32>, Vec<u8>), ExecError> {
+    // sshd starts the remote shell in a new session, so its pid is the process group of every
+    // process the command starts.
+    let pid_path = format!("/tmp/aws-ec2-{}.pid", uuid::Uuid::new_v4());
+    let command = pid_script(&pid_path, command);
+    let (code, output) = run_channel(session, &command, timeout, prefix, capture_stdout).await?;
+    if code.is_none() {
+        error!("Command timed out after {timeout:?}, killing it");
+        let kill = kill_script(&pid_path);
+        match run_channel(session, &kill, &KILL_TIMEOUT, prefix, false).await {
+            Ok((Some(0), _)) => {}
+            Ok((code, _)) => error!("Failed to kill command: {code:?}"),
+            Err(err) => error!("Failed to kill command: {err}"),
+        }
+    }
+    Ok((code, output))
+}
+
+/// Runs `command` in a subshell of a shell recording its pid in `pid_path` and removing it when it
+/// exits, for [`kill_script`].
+///
+/// The subshell keeps the `EXIT` trap removing the pid file from being replaced by one `command`
+/// sets.
+fn pid_script(pid_path: &str, command: &str) -> String {
+    format!("echo $$ > {pid_path}; trap 'rm -f {pid_path}' EXIT\n(\n{command}\n)")
+}
+
+/// Kills the process group of the shell which recorded its pid in `pid_path`, with `SIGTERM` then
+/// `SIGKILL` after the grace period.
+fn kill_script(pid_path: &str) -> String {
+    format!(
+        "pgid=$(cat {pid_path}) || exit 0; kill -TERM -$pgid; sleep {}; \
+        kill -KILL -$pgid 2>/dev/null; rm -f {pid_path}",
+        KILL_GRACE_PERIOD.as_secs()
+    )
+}
+
+/// Runs `command` on a new channel.
+///
+/// Returns `None` if the command didn't finish within `timeout`, it is left running.
+async fn run_channel(
+    session: &AsyncSession,
+    command: &str,
+    timeout: &Duration,
+    prefix: &str,
+    capture_stdout: bool,
+) -> Result<(Option<i32>, Vec<u8>), ExecError> {
+    #[allow(clippy::enum_glob_use)]
+    use ExecError::*;
+
+    let start = Instant::now();
+
+    // Opening channel
+    info!("Opening channel");
+    let open = session.run(|| {
+        session
+            .session()
+            .channel_session()
+            .map_err(std::io::Error::from)
+    });
+    let mut channel = tokio::time::timeout(*timeout, open)
+        .await
+        .map_err(|_| ChannelTimeout)?
+        .map_err(Channel)?;
+
+    // Running exec
+    info!("Running exec: {command:?}");
+    let run = session.run(|| channel.exec(command).map_err(std::io::Error::from));
+    tokio::time::timeout(timeout.saturating_sub(start.elapsed()), run)
+        .await
+        .map_err(|_| ExecTimeout)?
+        .map_err(Exec)?;
+
+    // Output
+    // ---------------------------------------------------------------------------------------------
+    // Both streams are read by one task, as reading either may buffer data for the other from the
+    // socket, so waiting on the socket is only correct once neither has data.
+    let mut stderr_stream = channel.stderr();
+    let (mut stdout_line, mut stderr_line, mut output) = (Vec::new(), Vec::new(), Vec::new());
+    let read = async {
+        let (mut stdout_open, mut stderr_open) = (true, true);
+        let mut buffer = [u8::default(); 1024];
+        while stdout_open || stderr_open {
+            let mut read_any = false;
+            if stdout_open {
+                match channel.read(&mut buffer) {
+                    Ok(0) => (stdout_open, read_any) = (false, true),
+                    Ok(n) if capture_stdout => {
+                        read_any = true;
+                        output.extend_from_slice(&buffer[..n]);
+                    }
+                    Ok(n) => {
+                        read_any = true;
+                        let stdout = &mut std::io::stdout();
+                        write_prefixed(stdout, prefix, &mut stdout_line, &buffer[..n]).unwrap();
+                    }
+                    Err(err) if err.kind() == WouldBlock => {}
+                    Err(err) => return Err(Stdout(err)),
+                }
+            }
+            if stderr_open {
+                match stderr_stream.read(&mut buffer) {
+                    Ok(0) => (stderr_open, read_any) = (false, true),
+                    Ok(n) => {
+                        read_any = true;
+                        let stderr = &mut std::io::stderr();
+                        write_prefixed(stderr, prefix, &mut stderr_line, &buffer[..n]).unwrap();
+                    }
+                    Err(err) if err.kind() == WouldBlock => {}
+                    Err(err) => return Err(Stderr(err)),
+                }
+            }
+            if !read_any {
+                session.wait().await.map_err(Stdout)?;
+            }
+        }
+        Ok(())
+    };
+    let finished = tokio::time::timeout(timeout.saturating_sub(start.elapsed()), read).await;
+    if !stdout_line.is_empty() {
+        write_prefixed(&mut std::io::stdout(), prefix, &mut stdout_line, b"\n").unwrap();
+    }
+    std::io::stdout().flush().unwrap();
+    if !stderr_line.is_empty() {
+        write_prefixed(&mut std::io::stderr(), prefix, &mut stderr_line, b"\n").unwrap();
+    }
+    std::io::stderr().flush().unwrap();
+    match finished {
+        Ok(result) => result?,
+        Err(_) => return Ok((None, output)),
+    }
+
+    // Exit
+    // ---------------------------------------------------------------------------------------------
+    info!("Waiting on close");
+    let close = session.run(|| channel.wait_close().map_err(std::io::Error::from));
+    match tokio::time::timeout(timeout.saturating_sub(start.elapsed()), close).await {
+        Ok(result) => result.map_err(Close)?,
+        Err(_) => return Ok((None, output)),
+    }
+    // A command killed by a signal has no exit status, so it is given the code a shell would.
+    let signal = channel.exit_signal().map_err(ExitSignal)?;
+    if let Some(name) = signal.exit_signal {
+        let code = signal_exit_code(&name);
+        let message = signal.error_message.unwrap_or_default();
+        error!("Command killed by SIG{name}, code {code} {message}");
+        return Ok((Some(code), output));
+    }
+    Ok((Some(channel.exit_status().map_err(Exit)?), output))
+}
+
+/// The conventional `128 + n` exit code of a process killed by the signal `name` (without the
+/// `SIG` prefix, as sent in an SSH `exit-signal` request).
+///
+/// The numbers are those of Linux on x86 and ARM. Unknown signals are given `255`.
+fn signal_exit_code(name: &str) -> i32 {
+    let number = match name {
+        "HUP" => 1,
+        "INT" => 2,
+        "QUIT" => 3,
+        "ILL" => 4,
+        "TRAP" => 5,
+        "ABRT" => 6,
+        "BUS" => 7,
+        "FPE" => 8,
+        "KILL" => 9,
+        "USR1" => 10,
+        "SEGV" => 11,
+        "USR2" => 12,
+        "PIPE" => 13,
+        "ALRM" => 14,
+        "TERM" => 15,
+        "XCPU" => 24,
+        "XFSZ" => 25,
+        "SYS" => 31,
+        _ => return 255,
+    };
+    128 + number
+}
+
+/// Writes `data` to `out` with each line prefixed by `prefix`.
+///
+/// An incomplete trailing line is held in `line` until a later call completes it, so output from
+/// instances running concurrently is never interleaved mid-line.
+fn write_prefixed(
+    out: &mut impl Write,
+    prefix: &str,
+    line: &mut Vec<u8>,
+    data: &[u8],
+) -> std::io::Result<()> {
+    let mut complete = Vec::new();
+    for byte in data {
+        if *byte == b'\n' {
+            complete.extend_from_slice(prefix.as_bytes());
+            complete.append(line);
+            complete.push(b'\n');
+        } else {
+            line.push(*byte);
+        }
+    }
+    out.write_all(&complete)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use std::net::TcpListener;
+
+    /// A transport which runs nothing, recording the commands run and files written.
+    #[derive(Default)]
+    struct FakeTransport {
+        /// The code each command exits with in turn, `None` for a timeout, after which they exit
+        /// with `0`.
+        codes: std::sync::Mutex<std::collections::VecDeque<Option<i32>>>,
+        /// The stdout of each command when it is captured.
+        stdout: Vec<u8>,
+        /// Commands containing any of these fail to run.
+        failing: Vec<&'static str>,
+        /// Shared so they can be checked once the transport is moved into an instance.
+        commands: Arc<std::sync::Mutex<Vec<String>>>,
+        files: Arc<std::sync::Mutex<Vec<(String, Vec<u8>)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for FakeTransport {
+        async fn exec(
+            &self,
+            command: &str,
+            _timeout: &Duration,
+            _prefix: &str,
+            capture_stdout: bool,
+        ) -> Result<(Option<i32>, Vec<u8>), ExecError> {
+            self.commands.lock().unwrap().push(String::from(command));
+            if self.failing.iter().any(|failing| command.contains(failing)) {
+                return Err(ExecError::ExecTimeout);
+            }
+            let code = self.codes.lock().unwrap().pop_front().unwrap_or(Some(0));
+            let stdout = if capture_stdout {
+                self.stdout.clone()
+            } else {
+                Vec::new()
+            };
+            Ok((code, stdout))
+        }
+
+        async fn write_file(
+            &self,
+            data: &[u8],
+            remote_path: &str,
+            _timeout: &Duration,
+        ) -> Result<(), MainError> {
+            let file = (String::from(remote_path), data.to_vec());
+            self.files.lock().unwrap().push(file);
+            Ok(())
+        }
+    }
+
+    /// A runner whose client is never used, and an instance connected over `transport`.
+    fn fake_instance(config: RunConfig, transport: FakeTransport) -> (Runner, Instance) {
+        let client = ec2::Client::from_conf(
+            ec2::Config::builder()
+                .region(ec2::config::Region::new("eu-west-2"))
+                .build(),
+        );
+        let instance = Instance {
+            id: String::from("i-0"),
+            address: String::from("203.0.113.1"),
+            public_ip: None,
+            private_ip: None,
+            user: String::from("ubuntu"),
+            transport: Box::new(transport),
+            prefix: String::new(),
+            remote_dir: None,
+            market: Market::OnDemand,
+        };
+        (Runner::new(client, config), instance)
+    }
+
+    /// A fake EC2 endpoint, recording the body of each request.
+    ///
+    /// Every instance type and AMI is compatible. `RunInstances` launches the instance immediately
+    /// but responds after [`FakeEc2State::launch_delay`], and every launched instance is found by
+    /// tag.
+    struct FakeEc2 {
+        client: ec2::Client,
+        requests: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    /// The resources of a [`FakeEc2`] and how it behaves.
+    #[derive(Default)]
+    struct FakeEc2State {
+        launch_delay: Duration,
+        /// Whether launched instances start running, rather than staying pending until they are
+        /// terminated.
+        boot: bool,
+        /// The available availability zones.
+        zones: Vec<&'static str>,
+        /// The id and availability zone of each subnet of the VPC.
+        subnets: Vec<(&'static str, &'static str)>,
+        /// The availability zones with spot capacity, `None` for every zone.
+        spot_zones: Option<Vec<&'static str>>,
+        /// How many times deleting a security group fails as it is still in use.
+        security_group_in_use: usize,
+        /// The id and state of each launched instance.
+        instances: Vec<(String, &'static str)>,
+        /// Whether getting the latest console output fails as it is unsupported.
+        latest_console_output_unsupported: bool,
+        /// The errors getting the console output fails with in turn, before it succeeds.
+        console_output_errors: Vec<&'static str>,
+    }
+
+    impl FakeEc2 {
+        fn new(state: FakeEc2State) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let requests = Arc::<std::sync::Mutex<Vec<String>>>::default();
+            let state = Arc::new(std::sync::Mutex::new(state));
+            std::thread::spawn({
+                let requests = requests.clone();
+                let state = state.clone();
+                move || {
+                    while let Ok((stream, _)) = listener.accept() {
+                        let requests = requests.clone();
+                        let state = state.clone();
+                        std::thread::spawn(move || Self::respond(stream, &requests, &state));
+                    }
+                }
+            });
+            let client = ec2::Client::from_conf(
+                ec2::Config::builder()
+                    .region(ec2::config::Region::new("eu-west-2"))
+                    .endpoint_url(format!("http://{address}"))
+                    .credentials_provider(ec2::config::Credentials::new(
+                        "access-key",
+                        "secret-key",
+                        None,
+                        None,
+                        "test",
+                    ))
+                    .retry_config(ec2::config::retry::RetryConfig::disabled())
+                    .build(),
+            );
+            Self { client, requests }
+        }
+
+        /// Answers the request on `stream`, then closes it.
+        fn respond(
+            stream: std::net::TcpStream,
+            requests: &std::sync::Mutex<Vec<String>>,
+            state: &std::sync::Mutex<FakeEc2State>,
+        ) {
+            let mut reader = std::io::BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_ascii_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let body = String::from_utf8(body).unwrap();
+            requests.lock().unwrap().push(body.clone());
+
+            let action = param(&body, "Action").unwrap();
+            let content = match action {
+                "RunInstances" => Self::run_instances(&body, state),
+                "DeleteSecurityGroup" => {
+                    let mut state = state.lock().unwrap();
+                    if state.security_group_in_use > 0 {
+                        state.security_group_in_use -= 1;
+                        Err("DependencyViolation")
+                    } else {
+                        Ok(String::from("<return>true</return>"))
+                    }
+                }
+                "GetConsoleOutput" => {
+                    let mut state = state.lock().unwrap();
+                    if state.latest_console_output_unsupported
+                        && param(&body, "Latest") == Some("true")
+                    {
+                        Err("UnsupportedOperation")
+                    } else if state.console_output_errors.is_empty() {
+                        let console = "-----BEGIN SSH HOST KEY FINGERPRINTS-----\n\
+                                       256 SHA256:abc root@ip-172-31-0-1 (ED25519)\n\
+                                       -----END SSH HOST KEY FINGERPRINTS-----\n";
+                        Ok(format!(
+                            "<instanceId>i-0</instanceId><output>{}</output>",
+                            aws_smithy_types::base64::encode(console)
+                        ))
+                    } else {
+                        Err(state.console_output_errors.remove(0))
+                    }
+                }
+                _ => Ok(Self::describe(action, &body, &mut state.lock().unwrap())),
+            };
+            let (status, response) = match content {
+                Ok(content) => (
+                    "200 OK",
+                    format!(
+                        "<{action}Response xmlns=\"http://ec2.amazonaws.com/doc/2016-11-15/\">\
+                         {content}</{action}Response>"
+                    ),
+                ),
+                Err(code) => (
+                    "400 Bad Request",
+                    format!(
+                        "<Response><Errors><Error><Code>{code}</Code><Message>fake</Message>\
+                         </Error></Errors><RequestID>0</RequestID></Response>"
+                    ),
+                ),
+            };
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {status}\r\ncontent-type: text/xml\r\ncontent-length: {}\r\n\
+                 connection: close\r\n\r\n{response}",
+                response.len()
+            )
+            .unwrap();
+        }
+
+        /// Launches an instance, unless it is a spot instance in a zone without spot capacity.
+        fn run_instances(
+            body: &str,
+            state: &std::sync::Mutex<FakeEc2State>,
+        ) -> Result<String, &'static str> {
+            let (id, launch_delay) = {
+                let mut state = state.lock().unwrap();
+                let zone = param(body, "Placement.AvailabilityZone").or_else(|| {
+                    let subnet = param(body, "NetworkInterface.1.SubnetId")?;
+                    let (_, zone) = state.subnets.iter().find(|(id, _)| *id == subnet)?;
+                    Some(*zone)
+                });
+                let spot = param(body, "InstanceMarketOptions.MarketType") == Some("spot");
+                if let (true, Some(spot_zones)) = (spot, &state.spot_zones) {
+                    if !zone.is_some_and(|zone| spot_zones.contains(&zone)) {
+                        return Err("InsufficientInstanceCapacity");
+                    }
+                }
+                let id = format!("i-{}", state.instances.len());
+                let instance_state = if state.boot { "running" } else { "pending" };
+                state.instances.push((id.clone(), instance_state));
+                (id, state.launch_delay)
+            };
+            std::thread::sleep(launch_delay);
+            Ok(format!(
+                "<instancesSet><item><instanceId>{id}</instanceId></item></instancesSet>"
+            ))
+        }
+
+        /// The content of the response to `action`, which can't fail.
+        fn describe(action: &str, body: &str, state: &mut FakeEc2State) -> String {
+            match action {
+                "DescribeInstanceTypeOfferings" => String::from(
+                    "<instanceTypeOfferingSet><item><instanceType>t3.micro</instanceType></item>\
+                     </instanceTypeOfferingSet>",
+                ),
+                "DescribeInstanceTypes" => String::from(
+                    "<instanceTypeSet><item><instanceType>t3.micro</instanceType></item>\
+                     </instanceTypeSet>",
+                ),
+                "DescribeImages" => {
+                    String::from("<imagesSet><item><imageId>ami-0</imageId></item></imagesSet>")
+                }
+                "CreateKeyPair" => String::from("<keyMaterial>key</keyMaterial>"),
+                "CreateSecurityGroup" => String::from("<groupId>sg-0</groupId>"),
+                "DescribeAvailabilityZones" => {
+                    let items = state.zones.iter().fold(String::new(), |items, zone| {
+                        items + &format!("<item><zoneName>{zone}</zoneName></item>")
+                    });
+                    format!("<availabilityZoneInfo>{items}</availabilityZoneInfo>")
+                }
+                "DescribeSubnets" => {
+                    let zone = filter_value(body, "availability-zone");
+                    let items = state
+                        .subnets
+                        .iter()
+                        .filter(|(_, subnet_zone)| zone.is_none_or(|zone| zone == *subnet_zone))
+                        .fold(String::new(), |items, (id, zone)| {
+                            items
+                                + &format!(
+                                    "<item><subnetId>{id}</subnetId><availabilityZone>{zone}\
+                                     </availabilityZone><vpcId>vpc-0</vpcId></item>"
+                                )
+                        });
+                    format!("<subnetSet>{items}</subnetSet>")
+                }
+                "DescribeInstanceStatus" => {
+                    let (id, state) = state
+                        .instances
+                        .iter()
+                        .find(|(id, _)| param(body, "InstanceId.1") == Some(id))
+                        .unwrap();
+                    format!(
+                        "<instanceStatusSet><item><instanceId>{id}</instanceId><instanceState>\
+                         <name>{state}</name></instanceState></item></instanceStatusSet>"
+                    )
+                }
+                "DescribeInstances" => {
+                    let items = state
+                        .instances
+                        .iter()
+                        .filter(|(_, state)| *state != "terminated")
+                        .fold(String::new(), |items, (id, state)| {
+                            items
+                                + &format!(
+                                    "<item><instanceId>{id}</instanceId><instanceState><name>\
+                                     {state}</name></instanceState></item>"
+                                )
+                        });
+                    format!(
+                        "<reservationSet><item><instancesSet>{items}</instancesSet></item>\
+                         </reservationSet>"
+                    )
+                }
+                "TerminateInstances" => {
+                    for (id, state) in &mut state.instances {
+                        if body.contains(&format!("={id}")) {
+                            *state = "terminated";
+                        }
+                    }
+                    String::new()
+                }
+                _ => String::from("<return>true</return>"),
+            }
+        }
+
+        /// The actions requested in order.
+        fn actions(&self) -> Vec<String> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|body| param(body, "Action").map(String::from))
+                .collect()
+        }
+
+        /// Waits until `action` has been requested.
+        async fn requested(&self, action: &str) {
+            while !self.actions().iter().any(|requested| requested == action) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+
+    /// The value of `key` in the form encoded `body`.
+    fn param<'a>(body: &'a str, key: &str) -> Option<&'a str> {
+        body.split('&').find_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            (name == key).then_some(value)
+        })
+    }
+
+    /// The first value of the filter `name` in the form encoded `body`.
+    fn filter_value<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+        let (key, _) = body
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, value)| key.starts_with("Filter.") && *value == name)?;
+        param(body, &format!("{}.Value.1", key.strip_suffix(".Name")?))
+    }
+
+    /// A config launching a single target from the AMI `ami-0`, without detecting public IPs.
+    fn fake_ec2_config() -> RunConfig {
+        RunConfig::new()
+            .target(Target::new(InstanceType::T3Micro, "ami-0"))
+            .user("ubuntu")
+            .security_group(SecurityGroup::Create {
+                name: None,
+                ssh_cidrs: vec![Cidr::from_str("203.0.113.0/24").unwrap()],
+            })
+    }
+
+    /// Returns a local address nothing is listening on.
+    fn unused_address() -> std::net::SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[test]
+    fn default_users_inference() {
+        assert_eq!(
+            default_users(
+                Some(CANONICAL_OWNER_ID),
+                Some("ubuntu/images/hvm-ssd/ubuntu-jammy-22.04-amd64-server-20230919")
+            ),
+            ["ubuntu"]
+        );
+        assert_eq!(
+            default_users(
+                Some("137112412989"),
+                Some("al2023-ami-2023.2.20231002.0-kernel-6.1-arm64")
+            ),
+            ["ec2-user"]
+        );
+        assert_eq!(
+            default_users(Some(DEBIAN_OWNER_ID), Some("debian-12-amd64-20231004-1523")),
+            ["admin"]
+        );
+        assert_eq!(
+            default_users(
+                Some("092701018921"),
+                Some("bottlerocket-aws-k8s-1.28-x86_64-v1.15.1")
+            ),
+            ["ec2-user"]
+        );
+        assert_eq!(
+            default_users(None, Some("my-custom-image")),
+            ["ubuntu", "ec2-user", "admin"]
+        );
+    }
+
+    #[test]
+    fn host_keys_parse() {
+        const CONSOLE: &str = "\
+[   12.345678] cloud-init[1234]: Cloud-init v. 23.3.1 finished
+-----BEGIN SSH HOST KEY FINGERPRINTS-----
+256 SHA256:Nh0Me49Zh9fDw/VYUfq43IJmI1T+XrjiYONPND8GzaE root@ip-172-31-0-1 (ECDSA)
+256 SHA256:Wz9kvPOwkP4HRRBydVpgvRpgdmp3ZR3CwV8BCc7Pt/Q root@ip-172-31-0-1 (ED25519)
+-----END SSH HOST KEY FINGERPRINTS-----
+-----BEGIN SSH HOST KEY KEYS-----
+ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 root@ip-172-31-0-1
+-----END SSH HOST KEY KEYS-----
+";
+        assert_eq!(
+            HostKeys::parse(CONSOLE),
+            Some(HostKeys {
+                fingerprints: vec![
+                    String::from("SHA256:Nh0Me49Zh9fDw/VYUfq43IJmI1T+XrjiYONPND8GzaE"),
+                    String::from("SHA256:Wz9kvPOwkP4HRRBydVpgvRpgdmp3ZR3CwV8BCc7Pt/Q"),
+                ],
+                keys: vec![b"\0\0\0\x0bssh-ed25519".to_vec()],
+            })
+        );
+        assert_eq!(HostKeys::parse("[    0.000000] Linux version 6.2.0"), None);
+    }
+
+    #[tokio::test]
+    async fn get_host_keys_retries() {
+        let host_keys = HostKeys {
+            fingerprints: vec![String::from("SHA256:abc")],
+            keys: Vec::new(),
+        };
+        let timeout = Duration::from_mins(1);
+        // Only unsupported latest output falls back to the buffered output.
+        for (unsupported, errors, latest) in [
+            (true, vec![], ["true", "false"]),
+            (false, vec!["InvalidInstanceID.NotFound"], ["true", "true"]),
+        ] {
+            let ec2 = FakeEc2::new(FakeEc2State {
+                latest_console_output_unsupported: unsupported,
+                console_output_errors: errors,
+                ..FakeEc2State::default()
+            });
+            let result = get_host_keys(&ec2.client, &timeout, "i-0").await;
+            assert_eq!(result.unwrap(), host_keys);
+            let requests = ec2.requests.lock().unwrap();
+            let requested = requests
+                .iter()
+                .map(|body| param(body, "Latest").unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(requested, latest);
+        }
+
+        // Errors are returned once timed out.
+        let ec2 = FakeEc2::new(FakeEc2State {
+            console_output_errors: vec!["InvalidInstanceID.NotFound"],
+            ..FakeEc2State::default()
+        });
+        let result = get_host_keys(&ec2.client, &Duration::ZERO, "i-0").await;
+        assert!(matches!(result, Err(MainError::GetConsoleOutput(_))));
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert_eq!(
+            Cidr::from_str("203.0.113.0/24").unwrap().to_string(),
+            "203.0.113.0/24"
+        );
+        assert_eq!(
+            Cidr::from_str("2001:db8::/32").unwrap().to_string(),
+            "2001:db8::/32"
+        );
+        assert_eq!(
+            Cidr::from("198.51.100.7".parse::<std::net::IpAddr>().unwrap()).to_string(),
+            "198.51.100.7/32"
+        );
+        assert!(Cidr::from_str("203.0.113.0").is_err());
+        assert!(Cidr::from_str("203.0.113.0/33").is_err());
+    }
+
+    #[test]
+    fn artifact_parse() {
+        assert_eq!(
+            Artifact::from_str("target/*.xml:reports").unwrap(),
+            Artifact {
+                glob: String::from("target/*.xml"),
+                local_dir: std::path::PathBuf::from("reports"),
+            }
+        );
+        assert_eq!(
+            Artifact::from_str("core.*:/tmp/a:b").unwrap().local_dir,
+            std::path::PathBuf::from("/tmp/a:b")
+        );
+        assert!(Artifact::from_str("target/*.xml").is_err());
+        assert!(Artifact::from_str(":reports").is_err());
+        assert!(Artifact::from_str("*.xml:").is_err());
+    }
+
+    #[test]
+    fn jump_host_parse() {
+        assert_eq!(
+            JumpHost::from_str("ec2-user@bastion.example.com").unwrap(),
+            JumpHost::new("ec2-user", "bastion.example.com", 22)
+        );
+        assert_eq!(
+            JumpHost::from_str("ubuntu@203.0.113.1:2222").unwrap(),
+            JumpHost::new("ubuntu", "203.0.113.1", 2222)
+        );
+        let ipv6 = JumpHost::from_str("admin@[2001:db8::1]:2222").unwrap();
+        assert_eq!(ipv6, JumpHost::new("admin", "2001:db8::1", 2222));
+        assert_eq!(ipv6.to_string(), "admin@[2001:db8::1]:2222");
+        assert!(JumpHost::from_str("bastion.example.com").is_err());
+        assert!(JumpHost::from_str("ubuntu@bastion:ssh").is_err());
+        assert!(JumpHost::from_str("admin@[2001:db8::1]2222").is_err());
+    }
+
+    #[test]
+    fn ami_spec_parse() {
+        assert_eq!(
+            AmiSpec::from_str("ami-0eb260c4d5475b901").unwrap(),
+            AmiSpec::Id(String::from("ami-0eb260c4d5475b901"))
+        );
+        let parameter = "/aws/service/debian/release/12/latest/amd64";
+        for spec in [
+            format!("ssm:{parameter}"),
+            format!("resolve:ssm:{parameter}"),
+        ] {
+            assert_eq!(
+                AmiSpec::from_str(&spec).unwrap(),
+                AmiSpec::Parameter(String::from(parameter))
+            );
+        }
+        assert_eq!(
+            AmiSpec::from_str("name:099720109477:ubuntu/images/*22.04*").unwrap(),
+            AmiSpec::Name {
+                owner: String::from("099720109477"),
+                pattern: String::from("ubuntu/images/*22.04*")
+            }
+        );
+        assert_eq!(
+            AmiSpec::from_str("name:my-image-*").unwrap(),
+            AmiSpec::Name {
+                owner: String::from("self"),
+                pattern: String::from("my-image-*")
+            }
+        );
+        let AmiSpec::Alias { x86_64, arm64 } = AmiSpec::from_str("al2023").unwrap() else {
+            panic!("al2023 is an alias");
+        };
+        assert!(x86_64.ends_with("x86_64") && arm64.ends_with("arm64"));
+        assert!(AmiSpec::from_str("ssm:").is_err());
+        assert!(AmiSpec::from_str("name:self:").is_err());
+        let err = AmiSpec::from_str("ubuntu").unwrap_err();
+        assert!(err.contains("ubuntu-22.04"));
+
+        let target = Target::new(InstanceType::T2Medium, format!("ssm:{parameter}"));
+        assert_eq!(
+            target.artifact_dir(),
+            Path::new("t2.medium/ssm:_aws_service_debian_release_12_latest_amd64")
+        );
+    }
+
+    #[test]
+    fn instance_type_image_incompatibilities() {
+        use ec2::types::{
+            ArchitectureType, ArchitectureValues, BootModeType, BootModeValues, EbsInfo,
+            EbsNvmeSupport, EnaSupport, Image, InstanceTypeInfo, NetworkInfo, ProcessorInfo,
+            VirtualizationType,
+        };
+
+        let t4g = InstanceTypeInfo::builder()
+            .instance_type(InstanceType::T4gMedium)
+            .processor_info(
+                ProcessorInfo::builder()
+                    .supported_architectures(ArchitectureType::Arm64)
+                    .build(),
+            )
+            .supported_virtualization_types(VirtualizationType::Hvm)
+            .supported_boot_modes(BootModeType::Uefi)
+            .network_info(
+                NetworkInfo::builder()
+                    .ena_support(EnaSupport::Required)
+                    .build(),
+            )
+            .ebs_info(
+                EbsInfo::builder()
+                    .nvme_support(EbsNvmeSupport::Required)
+                    .build(),
+            )
+            .build();
+        let arm64 = Image::builder()
+            .image_id("ami-0")
+            .architecture(ArchitectureValues::Arm64)
+            .virtualization_type(VirtualizationType::Hvm)
+            .boot_mode(BootModeValues::UefiPreferred)
+            .ena_support(true)
+            .build();
+        let x86_64 = Image::builder()
+            .image_id("ami-1")
+            .architecture(ArchitectureValues::X8664)
+            .virtualization_type(VirtualizationType::Paravirtual)
+            .boot_mode(BootModeValues::LegacyBios)
+            .build();
+
+        let incompatibilities = |info, ami, image| {
+            incompatibilities(&InstanceType::T4gMedium, "eu-west-2", info, ami, image)
+        };
+        assert!(incompatibilities(Some(&t4g), "ami-0", Some(&arm64)).is_empty());
+        assert_eq!(
+            incompatibilities(Some(&t4g), "ami-1", Some(&x86_64)),
+            [
+                "AMI ami-1 is x86_64 but t4g.medium supports arm64",
+                "AMI ami-1 uses paravirtual virtualization but t4g.medium supports hvm",
+                "AMI ami-1 boots with legacy-bios but t4g.medium supports uefi",
+                "t4g.medium requires ENA and NVMe drivers but AMI ami-1 doesn't support ENA",
+            ]
+        );
+        assert_eq!(
+            incompatibilities(None, "ami-0", Some(&arm64)),
+            ["instance type t4g.medium is not offered in eu-west-2"]
+        );
+        assert_eq!(
+            incompatibilities(Some(&t4g), "ami-2", None),
+            ["AMI ami-2 not found in eu-west-2"]
+        );
+    }
+
+    #[test]
+    fn signal_exit_codes() {
+        assert_eq!(signal_exit_code("KILL"), 137);
+        assert_eq!(signal_exit_code("SEGV"), 139);
+        assert_eq!(signal_exit_code("TERM"), 143);
+        assert_eq!(signal_exit_code("NOPE"), 255);
+    }
+
+    #[test]
+    fn shell_quote_literal() {
+        assert_eq!(shell_quote("aws-ec2"), "'aws-ec2'");
+        assert_eq!(shell_quote("/tmp/a b"), "'/tmp/a b'");
+        assert_eq!(shell_quote("it's $HOME"), "'it'\\''s $HOME'");
+    }
+
+    #[test]
+    fn summary_table_aligns() {
+        let reports = vec![(
+            String::from("t2.medium/ami-0"),
+            Market::Spot,
+            vec![
+                StepReport {
+                    name: String::from("build"),
+                    status: StepStatus::Failed(101, false),
+                    started: Some(std::time::UNIX_EPOCH),
+                    duration: Some(Duration::from_millis(1500)),
+                },
+                StepReport {
+                    name: String::from("test"),
+                    status: StepStatus::Skipped,
+                    started: None,
+                    duration: None,
+                },
+            ],
+        )];
+        assert_eq!(
+            summary_table(&reports),
+            "TARGET           MARKET  STEP   STATUS           STARTED               DURATION\n\
+             t2.medium/ami-0  spot    build  failed with 101  1970-01-01T00:00:00Z  1.5s\n\
+             t2.medium/ami-0  spot    test   skipped          -                     -"
+        );
+    }
+
+    #[test]
+    fn archive_ignores() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let files = [
+            (".gitignore", "target/\n*.log\n"),
+            (".awsec2ignore", "secret.txt\n"),
+            ("Cargo.toml", ""),
+            ("src/main.rs", ""),
+            ("src/.ignore", "generated.rs\n"),
+            ("src/generated.rs", ""),
+            ("target/debug/binary", ""),
+            ("build.log", ""),
+            ("keep/.gitignore", "!important.log\n"),
+            ("keep/important.log", ""),
+            ("secret.txt", ""),
+            ("docs/guide.md", ""),
+            (".git/HEAD", ""),
+        ];
+        for (path, contents) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+
+        let archived = |include: &[&str], exclude: &[&str]| {
+            let source = Source {
+                path: dir.display().to_string(),
+                include: include.iter().copied().map(String::from).collect(),
+                exclude: exclude.iter().copied().map(String::from).collect(),
+                remote_dir: String::from(DEFAULT_REMOTE_DIR),
+            };
+            let data = archive(&source).unwrap();
+            let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(data.as_slice()));
+            let mut files = tar
+                .entries()
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .filter(|entry| entry.header().entry_type().is_file())
+                .map(|entry| entry.path().unwrap().display().to_string())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+
+        assert_eq!(
+            archived(&[], &[]),
+            [
+                ".awsec2ignore",
+                ".gitignore",
+                "Cargo.toml",
+                "docs/guide.md",
+                "keep/.gitignore",
+                "keep/important.log",
+                "src/.ignore",
+                "src/main.rs",
+            ]
+        );
+        assert_eq!(
+            archived(&["*.rs", "Cargo.toml"], &[]),
+            ["Cargo.toml", "src/main.rs"]
+        );
+        assert_eq!(
+            archived(&[], &["docs/**", ".*ignore"]),
+            ["Cargo.toml", "keep/important.log", "src/main.rs",]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn steps_over_transport() {
+        let config = RunConfig::new()
+            .step(Step::new("build", "make"))
+            .step(Step::new("lint", "make lint").continue_on_error(true))
+            .step(Step::new("test", "make test"))
+            .step(Step::new("package", "make package"))
+            .step(Step::new("cleanup", "make clean").always(true));
+        let transport = FakeTransport {
+            codes: std::sync::Mutex::new([Some(0), Some(1), Some(3)].into()),
+            ..FakeTransport::default()
+        };
+        let commands = transport.commands.clone();
+        let (runner, mut instance) = fake_instance(config, transport);
+        instance.remote_dir = Some(String::from("aws-ec2"));
+
+        let (code, reports) = runner.run_steps(&instance).await.unwrap();
+        assert_eq!(code, Some(3));
+        let statuses = reports
+            .iter()
+            .map(|report| (report.name.as_str(), report.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                ("build", StepStatus::Succeeded),
+                ("lint", StepStatus::Failed(1, true)),
+                ("test", StepStatus::Failed(3, false)),
+                ("package", StepStatus::Skipped),
+                ("cleanup", StepStatus::Succeeded),
+            ]
+        );
+        assert_eq!(
+            *commands.lock().unwrap(),
+            ["make", "make lint", "make test", "make clean"]
+                .map(|command| format!("cd 'aws-ec2' || exit 1\n{command}"))
+        );
+    }
+
+    #[tokio::test]
+    async fn transfers_over_transport() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(dir.join("source")).unwrap();
+        std::fs::write(dir.join("source/main.rs"), "fn main() {}").unwrap();
+        std::fs::create_dir_all(dir.join("remote")).unwrap();
+        std::fs::write(dir.join("remote/report.xml"), "<testsuites/>").unwrap();
+        let remote = Source::new(dir.join("remote").display().to_string());
+
+        let config = RunConfig::new().source(Source::new(dir.join("source").display().to_string()));
+        let transport = FakeTransport {
+            stdout: archive(&remote).unwrap(),
+            ..FakeTransport::default()
+        };
+        let (commands, files) = (transport.commands.clone(), transport.files.clone());
+        let (runner, mut instance) = fake_instance(config, transport);
+
+        runner.upload(&mut instance).await.unwrap();
+        assert_eq!(instance.remote_dir.as_deref(), Some(DEFAULT_REMOTE_DIR));
+        let files = files.lock().unwrap().clone();
+        let [(remote_path, data)] = files.as_slice() else {
+            panic!("{files:?}");
+        };
+        assert!(remote_path.starts_with("/tmp/") && remote_path.ends_with(".tar.gz"));
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(data.as_slice()));
+        let paths = tar
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect::<Vec<_>>();
+        assert!(paths.contains(&String::from("main.rs")), "{paths:?}");
+        assert!(commands.lock().unwrap()[0].contains("tar -xf"));
+
+        let artifact = Artifact::new("*.xml", dir.join("reports"));
+        runner.download(&instance, &artifact).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("reports/report.xml")).unwrap(),
+            "<testsuites/>"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_for_ssh_late_listener() {
+        const BANNER: &str = "SSH-2.0-test\r\n";
+        let address = unused_address();
+        let delay = Duration::from_secs(1);
+
+        // Like sshd on a booting instance the listener starts late, and at first closes
+        // connections without sending a banner.
+        let server = std::thread::spawn(move || {
+            std::thread::sleep(delay);
+            let listener = TcpListener::bind(address).unwrap();
+            drop(listener.accept().unwrap());
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(BANNER.as_bytes()).unwrap();
+            stream
+        });
+
+        let start = Instant::now();
+        let tcp = wait_for_ssh(&address, &Duration::from_secs(30))
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= delay);
+        tcp.set_nonblocking(false).unwrap();
+
+        // The banner is left for the SSH handshake.
+        let mut banner = String::new();
+        std::io::BufReader::new(tcp).read_line(&mut banner).unwrap();
+        assert_eq!(banner, BANNER);
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_for_ssh_timeout() {
+        let address = unused_address();
+        let timeout = Duration::from_secs(2);
+
+        let start = Instant::now();
+        let result = wait_for_ssh(&address, &timeout).await;
+        assert!(matches!(result, Err(MainError::SshReadyTimeout(_))));
+        assert!(start.elapsed() <= timeout + SSH_READY_ATTEMPT_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn wait_for_ssh_no_banner() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Accepts connections but never sends a banner.
+        let _server = std::thread::spawn(move || {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept() {
+                streams.push(stream);
+            }
+        });
+
+        let result = wait_for_ssh(&address, &Duration::from_secs(2)).await;
+        assert!(matches!(result, Err(MainError::SshReadyTimeout(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown_during_launch_cleans_up() {
+        // Shuts down while waiting for the instance to start, and while a target on another
+        // thread is waiting for `RunInstances` to respond.
+        let launch_delay = Duration::from_secs(1);
+        for (action, launch_delay) in [
+            ("DescribeInstanceStatus", Duration::ZERO),
+            ("RunInstances", launch_delay),
+        ] {
+            let ec2 = FakeEc2::new(FakeEc2State {
+                launch_delay,
+                ..FakeEc2State::default()
+            });
+            let runner = Runner::new(ec2.client.clone(), fake_ec2_config());
+
+            let shutdown = ec2.requested(action);
+            let (end, cleaned) = run_all(std::slice::from_ref(&runner), shutdown).await;
+            assert!(matches!(end, RunEnd::Shutdown(())));
+            assert!(cleaned);
+
+            // Every target has stopped, so nothing is recorded after cleaning up.
+            assert!(runner.targets.try_lock().unwrap().is_empty());
+            tokio::time::sleep(launch_delay).await;
+            assert_eq!(runner.cleanup.resources(), []);
+
+            let actions = ec2.actions();
+            let cleanup = &actions[actions
+                .iter()
+                .position(|a| a == "TerminateInstances")
+                .unwrap()..];
+            assert_eq!(
+                cleanup
+                    .iter()
+                    .filter(|action| *action != "DescribeInstanceStatus")
+                    .collect::<Vec<_>>(),
+                ["TerminateInstances", "DeleteSecurityGroup", "DeleteKeyPair"],
+                "{action}"
+            );
+            assert!(ec2.requests.lock().unwrap().iter().any(|body| {
+                param(body, "Action") == Some("TerminateInstances") && body.contains("=i-0")
+            }));
+        }
+    }
+
+    #[tokio::test]
+    async fn deadline_during_launch_cleans_up() {
+        // The deadline is exceeded before `RunInstances` responds.
+        let ec2 = FakeEc2::new(FakeEc2State {
+            launch_delay: Duration::from_mins(1),
+            ..FakeEc2State::default()
+        });
+        let deadline = Duration::from_secs(1);
+        let runner = Runner::new(ec2.client.clone(), fake_ec2_config().deadline(deadline));
+
+        let (end, cleaned) = run_all(&[runner], std::future::pending::<()>()).await;
+        assert!(matches!(
+            end,
+            RunEnd::Finished(Err(MainError::DeadlineExceeded(d))) if d == deadline
+        ));
+        assert!(cleaned);
+        assert!(ec2
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|body| body.contains("Action=TerminateInstances") && body.contains("=i-0")));
+    }
+
+    #[tokio::test]
+    async fn cleanup_deletes_linger_key() {
+        let ec2 = FakeEc2::new(FakeEc2State::default());
+        let config = fake_ec2_config().linger(Linger::new(Duration::from_mins(1)));
+        let runner = Runner::new(ec2.client.clone(), config);
+        let key_path = linger_key_path(&runner.key_name);
+
+        let shutdown = async {
+            ec2.requested("DescribeInstanceStatus").await;
+            assert!(key_path.exists());
+        };
+        let (_, cleaned) = run_all(&[runner], shutdown).await;
+        assert!(cleaned);
+        assert!(!key_path.exists());
+    }
+
+    #[test]
+    fn cleanup_deletion_order() {
+        let resources = vec![
+            Resource::KeyPair(String::from("key")),
+            Resource::SecurityGroup(String::from("sg-0")),
+            Resource::Instance(String::from("i-0")),
+            Resource::SecurityGroup(String::from("sg-1")),
+            Resource::Instance(String::from("i-1")),
+        ];
+        assert_eq!(
+            deletion_order(resources),
+            (
+                vec![String::from("i-0"), String::from("i-1")],
+                vec![
+                    Resource::SecurityGroup(String::from("sg-1")),
+                    Resource::SecurityGroup(String::from("sg-0")),
+                    Resource::KeyPair(String::from("key")),
+                ]
+            )
+        );
+        assert_eq!(deletion_order(Vec::new()), (Vec::new(), Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn cleanup_retries_security_group_in_use() {
+        let ec2 = FakeEc2::new(FakeEc2State {
+            security_group_in_use: 1,
+            ..FakeEc2State::default()
+        });
+        let runner = Runner::new(ec2.client.clone(), fake_ec2_config());
+        runner.security_group_id().await.unwrap();
+
+        assert!(runner.cleanup().await);
+        let deletes = ec2
+            .actions()
+            .into_iter()
+            .filter(|action| action == "DeleteSecurityGroup")
+            .count();
+        assert_eq!(deletes, 2);
+        assert_eq!(runner.cleanup.resources(), []);
+    }
+
+    #[test]
+    fn cleanup_delete_retry() {
+        let timeout = Duration::from_mins(5);
+        let in_use = Some("DependencyViolation");
+        assert_eq!(
+            delete_retry(in_use, Duration::ZERO, &timeout),
+            DeleteRetry::Retry
+        );
+        assert_eq!(delete_retry(in_use, timeout, &timeout), DeleteRetry::Retry);
+        assert_eq!(
+            delete_retry(in_use, timeout + Duration::from_secs(1), &timeout),
+            DeleteRetry::TimedOut
+        );
+        assert_eq!(
+            delete_retry(Some("InvalidGroup.NotFound"), Duration::ZERO, &timeout),
+            DeleteRetry::Failed
+        );
+        assert_eq!(
+            delete_retry(None, Duration::ZERO, &timeout),
+            DeleteRetry::Failed
+        );
+    }
+
+    #[test]
+    fn kill_script_generation() {
+        assert_eq!(
+            kill_script("/tmp/a.pid"),
+            "pgid=$(cat /tmp/a.pid) || exit 0; kill -TERM -$pgid; sleep 5; \
+             kill -KILL -$pgid 2>/dev/null; rm -f /tmp/a.pid"
+        );
+    }
+
+    #[test]
+    fn pid_script_keeps_cleanup_trap() {
+        let pid_path = std::env::temp_dir().join(format!("aws-ec2-{}.pid", uuid::Uuid::new_v4()));
+        let pid_path = pid_path.to_str().unwrap();
+        // The command replaces the `EXIT` trap and exits early, with a comment on its last line.
+        let command = "trap 'echo trapped' EXIT\ntest -e \"$1\" || exit 2\nexit 3 # done";
+        let output = std::process::Command::new("sh")
+            .args(["-c", &pid_script(pid_path, command), "sh", pid_path])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"trapped\n");
+        assert!(!Path::new(pid_path).exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn kill_script_kills_process_group() {
+        let dir = std::env::temp_dir().join(format!("aws-ec2-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let pid_path = dir.join("command.pid");
+        let pid_path = pid_path.to_str().unwrap();
+        let child_path = dir.join("child.pid");
+
+        // Like sshd, the command's shell is started in a new session.
+        let command = pid_script(
+            pid_path,
+            &format!("sleep 60 & echo $! > {}; wait", child_path.display()),
+        );
+        let mut shell = std::process::Command::new("setsid")
+            .args(["sh", "-c", &command])
+            .spawn()
+            .unwrap();
+        while !child_path.exists() || std::fs::read_to_string(&child_path).unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let child = std::fs::read_to_string(&child_path).unwrap();
+
+        let status = std::process::Command::new("sh")
+            .args(["-c", &kill_script(pid_path)])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(!shell.wait().unwrap().success());
+        assert!(!Path::new(pid_path).exists());
+        // The background child was killed too, though it may not be reaped yet.
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", child.trim()));
+        assert!(stat.map_or(true, |stat| stat.contains(") Z ")));
+
+        // Nothing is killed once the command has exited.
+        let status = std::process::Command::new("sh")
+            .args(["-c", &kill_script(pid_path)])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// A `RunInstances` error with the given code.
+    fn run_instances_error(code: &str) -> MainError {
+        let err = ec2::operation::run_instances::RunInstancesError::generic(
+            aws_smithy_types::error::ErrorMetadata::builder()
+                .code(code)
+                .build(),
+        );
+        MainError::RunInstances(aws_smithy_http::result::SdkError::service_error(
+            err,
+            http::Response::new(aws_smithy_http::body::SdkBody::empty()),
+        ))
+    }
+
+    #[test]
+    fn spot_other_zones_decision() {
+        let spot = Spot::new().other_zones(true);
+        assert!(tries_other_zones(&spot, &Network::new()));
+        assert!(tries_other_zones(&spot, &Network::new().vpc_id("vpc-0")));
+        assert!(!tries_other_zones(&Spot::new(), &Network::new()));
+        // The zone is fixed.
+        assert!(!tries_other_zones(
+            &spot,
+            &Network::new().availability_zone("eu-west-2a"),
+        ));
+        assert!(!tries_other_zones(
+            &spot,
+            &Network::new().subnet_id("subnet-0"),
+        ));
+
+        // Only the zones a VPC has subnets in are tried.
+        let subnet = |id: &str, zone: &str| {
+            ec2::types::Subnet::builder()
+                .subnet_id(id)
+                .availability_zone(zone)
+                .build()
+        };
+        assert_eq!(
+            subnet_zones(&[
+                subnet("subnet-c", "eu-west-2c"),
+                subnet("subnet-a", "eu-west-2a"),
+                subnet("subnet-a2", "eu-west-2a"),
+            ]),
+            ["eu-west-2a", "eu-west-2c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn launch_spot_in_vpc_tries_zones_with_subnets() {
+        // The VPC has no subnet in `eu-west-2b`, and only `eu-west-2c` has spot capacity.
+        let ec2 = FakeEc2::new(FakeEc2State {
+            boot: true,
+            zones: vec!["eu-west-2a", "eu-west-2b", "eu-west-2c"],
+            subnets: vec![("subnet-a", "eu-west-2a"), ("subnet-c", "eu-west-2c")],
+            spot_zones: Some(vec!["eu-west-2c"]),
+            ..FakeEc2State::default()
+        });
+        let spot = Spot::new().other_zones(true);
+        let config = fake_ec2_config()
+            .network(Network::new().vpc_id("vpc-0"))
+            .spot(spot.clone());
+        let runner = Runner::new(ec2.client.clone(), config);
+        let target = Target::new(InstanceType::T3Micro, "ami-0");
+
+        let (_, id) = runner.launch_spot(&target, "sg-0", &spot).await.unwrap();
+        assert_eq!(id, "i-0");
+        let subnets = ec2
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|body| param(body, "Action") == Some("RunInstances"))
+            .map(|body| String::from(param(body, "NetworkInterface.1.SubnetId").unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(subnets, ["subnet-a", "subnet-c"]);
+        runner.cleanup().await;
+    }
+
+    #[test]
+    fn spot_on_demand_fallback_decision() {
+        let spot = Spot::new().on_demand_fallback(true);
+        let capacity = run_instances_error("MaxSpotInstanceCountExceeded");
+        let interrupted =
+            MainError::SpotInterrupted(String::from("i-0"), String::from("instance-terminated"));
+        assert!(on_demand_fallback(&spot, &capacity));
+        assert!(on_demand_fallback(&spot, &interrupted));
+        assert!(!on_demand_fallback(
+            &spot,
+            &run_instances_error("UnauthorizedOperation")
+        ));
+        assert!(!on_demand_fallback(
+            &spot,
+            &MainError::CreateKeyPairMaterial
+        ));
+        assert!(!on_demand_fallback(&Spot::new(), &capacity));
+        assert!(!on_demand_fallback(&Spot::new(), &interrupted));
+    }
+
+    #[test]
+    fn subnet_selection() {
+        let names_values = |filters: Vec<ec2::types::Filter>| {
+            filters
+                .iter()
+                .map(|filter| {
+                    (
+                        String::from(filter.name().unwrap()),
+                        filter.values().unwrap().join(","),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let pair = |name: &str, value: &str| (String::from(name), String::from(value));
+
+        let network = Network::new().vpc_id("vpc-0");
+        assert_eq!(
+            names_values(subnet_filters(&network, "vpc-0", None)),
+            [pair("vpc-id", "vpc-0"), pair("state", "available")]
+        );
+        let network = network
+            .availability_zone("eu-west-2a")
+            .subnet_tag("tier", "private")
+            .subnet_tag("team", "ci");
+        assert_eq!(
+            names_values(subnet_filters(&network, "vpc-0", None)),
+            [
+                pair("vpc-id", "vpc-0"),
+                pair("state", "available"),
+                pair("availability-zone", "eu-west-2a"),
+                pair("tag:tier", "private"),
+                pair("tag:team", "ci"),
+            ]
+        );
+        // A zone being retried overrides the configured one.
+        assert!(
+            names_values(subnet_filters(&network, "vpc-0", Some("eu-west-2b")))
+                .contains(&pair("availability-zone", "eu-west-2b"))
+        );
+
+        let subnet = |id: &str, count: i32| {
+            ec2::types::Subnet::builder()
+                .subnet_id(id)
+                .available_ip_address_count(count)
+                .build()
+        };
+        assert_eq!(select_subnet(&[]), None);
+        assert_eq!(
+            select_subnet(&[
+                subnet("subnet-a", 10),
+                subnet("subnet-c", 200),
+                subnet("subnet-b", 200),
+            ]),
+            Some(String::from("subnet-b"))
+        );
+    }
+
+    #[tokio::test]
+    async fn subnet_selection_in_vpc() {
+        let ec2 = FakeEc2::new(FakeEc2State {
+            subnets: vec![
+                ("subnet-b", "eu-west-2a"),
+                ("subnet-a", "eu-west-2a"),
+                ("subnet-c", "eu-west-2c"),
+            ],
+            ..FakeEc2State::default()
+        });
+        let network = Network::new().vpc_id("vpc-0").subnet_tag("tier", "ci");
+        let runner = Runner::new(ec2.client.clone(), fake_ec2_config().network(network));
+
+        assert_eq!(
+            runner.subnet_id(None).await.unwrap(),
+            Some(String::from("subnet-a"))
+        );
+        assert_eq!(
+            runner.subnet_id(Some("eu-west-2c")).await.unwrap(),
+            Some(String::from("subnet-c"))
+        );
+        assert!(matches!(
+            runner.subnet_id(Some("eu-west-2b")).await,
+            Err(MainError::NoSubnet(vpc)) if vpc == "vpc-0"
+        ));
+        let requests = ec2.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        for body in requests.iter() {
+            assert_eq!(filter_value(body, "vpc-id"), Some("vpc-0"));
+            assert_eq!(filter_value(body, "tag%3Atier"), Some("ci"));
+        }
+    }
+
+    #[tokio::test]
+    async fn subnet_selection_by_id() {
+        let network = Network::new().vpc_id("vpc-0").subnet_id("subnet-0");
+        let (runner, _) =
+            fake_instance(RunConfig::new().network(network), FakeTransport::default());
+        assert_eq!(
+            runner.subnet_id(Some("eu-west-2b")).await.unwrap(),
+            Some(String::from("subnet-0"))
+        );
+
+        // Without a VPC the default VPC chooses the subnet.
+        let (runner, _) = fake_instance(RunConfig::new(), FakeTransport::default());
+        assert_eq!(runner.subnet_id(None).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn artifacts_downloaded_after_failure() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(dir.join("remote")).unwrap();
+        std::fs::write(dir.join("remote/report.xml"), "<testsuites/>").unwrap();
+        let remote = Source::new(dir.join("remote").display().to_string());
+        let artifacts = [
+            Artifact::new("*.log", dir.join("logs")),
+            Artifact::new("*.xml", dir.join("reports")),
+        ];
+
+        // The step fails, then the first artifact fails to download.
+        let transport = FakeTransport {
+            codes: std::sync::Mutex::new([Some(3), Some(2)].into()),
+            stdout: archive(&remote).unwrap(),
+            ..FakeTransport::default()
+        };
+        let commands = transport.commands.clone();
+        let (runner, mut instance) = fake_instance(RunConfig::new(), transport);
+        let (code, _) = runner
+            .run_on_instance(&mut instance, &artifacts)
+            .await
+            .unwrap();
+        assert_eq!(code, Some(3));
+        assert_eq!(commands.lock().unwrap().len(), 3);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("reports/report.xml")).unwrap(),
+            "<testsuites/>"
+        );
+
+        // The failed download fails the target when the step succeeds.
+        let transport = FakeTransport {
+            codes: std::sync::Mutex::new([Some(0), Some(2)].into()),
+            stdout: archive(&remote).unwrap(),
+            ..FakeTransport::default()
+        };
+        let (runner, mut instance) = fake_instance(RunConfig::new(), transport);
+        let result = runner.run_on_instance(&mut instance, &artifacts).await;
+        assert!(matches!(result, Err(MainError::ArtifactFailed(glob, 2)) if glob == "*.log"));
+
+        // Artifacts are still downloaded when a step fails to run.
+        std::fs::remove_dir_all(dir.join("reports")).unwrap();
+        let transport = FakeTransport {
+            stdout: archive(&remote).unwrap(),
+            failing: vec![DEFAULT_COMMAND],
+            ..FakeTransport::default()
+        };
+        let (runner, mut instance) = fake_instance(RunConfig::new(), transport);
+        let result = runner.run_on_instance(&mut instance, &artifacts).await;
+        assert!(matches!(result, Err(MainError::Exec(_))), "{result:?}");
+        assert!(dir.join("reports/report.xml").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
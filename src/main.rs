@@ -1,812 +1,625 @@
-#![feature(if_let_guard)]
-#![feature(let_chains)]
-#![feature(async_closure)]
 #![warn(clippy::pedantic)]
-#![allow(clippy::type_complexity)]
 
+use aws_ec2::{
+    gc, run_all, Artifact, Cidr, Connect, JumpHost, Linger, Network, RunConfig, RunEnd, Runner,
+    SecurityGroup, Source, Spot, Step, Target, TransportKind, VolumeSize, DEFAULT_COMMAND,
+    DEFAULT_COMMAND_TIMEOUT_SECS, DEFAULT_LAUNCH_TIMEOUT_SECS, DEFAULT_SIZE,
+    DEFAULT_SSH_TIMEOUT_SECS, DEFAULT_TRANSFER_TIMEOUT_SECS, INFRASTRUCTURE_EXIT_CODE,
+    TIMEOUT_EXIT_CODE,
+};
 use aws_sdk_ec2 as ec2;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use config::{ConfigFile, NetworkSettings, Settings, SpotSettings, TargetSettings};
 use ec2::types::InstanceType;
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use std::io::ErrorKind;
-
-use std::io::ErrorKind::WouldBlock;
-use std::io::Read;
-use std::io::Write;
-use std::path::Path;
-
+use std::path::PathBuf;
 use std::process::ExitCode;
-use std::str::FromStr;
-
-use std::thread::sleep;
 use std::time::Duration;
-use std::time::Instant;
-use tracing::info;
-
-/// The default port used by ec2 for ssh.
-const EC2_SSH_PORT: VolumeSize = 22;
-
-// TODO This should only be default for optional command line argument.
-const INSTANCE_POLL_STATE_SLEEP: Duration = Duration::from_secs(1);
-
-// TODO This should only be default for optional command line argument.
-const SECURITY_GROUP_DESCRIPTION: &str = "test-aws-security-group-description";
-
-/// Default command to run on the host.
-const DEFAULT_COMMAND: &str = "cat /proc/cpuinfo && uname -a && ls";
-
-// TODO Remove this.
-const RUN_BUFFER: Duration = Duration::from_secs(30);
-
-// TODO Replace this with polling.
-const SSH_STARTUP_BUFFER: Duration = Duration::from_secs(20);
-
-// // TODO Replace this with polling so it doesn't need to wait longer than neccessary.
-// /// We need to wait a long time for the instance to be terminated and for the security group to lose
-// /// its dependency so it can be deleted.
-// const DELETE_SECURITY_GROUP_BUFFER: Duration = Duration::from_secs(120);
-
-const DEFAULT_COMMAND_TIMEOUT_SECS: u64 = 300;
+use tracing::{error, info};
 
-const DEFAULT_SIZE: VolumeSize = 16;
+mod config;
 
-type VolumeSize = u16;
+/// By default `gc` only deletes resources older than a day, so it doesn't delete the resources of
+/// runs which are still in progress.
+const DEFAULT_GC_OLDER_THAN_SECS: u64 = 24 * 60 * 60;
 
 #[derive(Parser, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    subcommand: Option<Subcommand>,
+    /// The configuration file defining targets and jobs, by default `aws-ec2.toml` in the current
+    /// directory if it exists.
+    ///
+    /// Command line arguments override the values in the configuration file.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// The job in the configuration file to run, required if it defines more than one.
+    #[arg(long)]
+    job: Option<String>,
+    /// Print the effective configuration, merged from the configuration file and the command line
+    /// arguments, as TOML and exit.
+    #[arg(long)]
+    print_config: bool,
+    /// A local directory to copy to each instance before running the command.
+    ///
+    /// Files ignored by `.gitignore`, `.ignore` or `.awsec2ignore` files are not copied.
     #[arg(long)]
     path: Option<String>,
+    /// Only copy files in `--path` matching these globs, comma separated or repeated.
+    #[arg(long, value_delimiter = ',', requires = "path")]
+    include: Vec<String>,
+    /// Don't copy files in `--path` matching these globs, comma separated or repeated.
+    #[arg(long, value_delimiter = ',', requires = "path")]
+    exclude: Vec<String>,
+    /// The directory on each instance `--path` is extracted into and the command is run in.
+    ///
+    /// Relative paths are relative to the home directory of the login user, by default `aws-ec2`.
+    #[arg(long, requires = "path")]
+    remote_dir: Option<String>,
     /// Name of the SSH key pair used.
     #[arg(long)]
     key_name: Option<String>,
     /// The name to use for the security group for instances.
     #[arg(long)]
     security_group_name: Option<String>,
-    /// Timeout in seconds.
+    /// The id of an existing security group to use instead of creating one.
+    ///
+    /// The security group must already allow SSH ingress from this machine.
+    #[arg(long, conflicts_with_all = ["security_group_name", "ssh_cidr"])]
+    security_group_id: Option<String>,
+    /// The CIDR blocks to allow SSH ingress from, comma separated or repeated (e.g.
+    /// `203.0.113.0/24,2001:db8::/32`).
+    ///
+    /// By default only the public IP addresses of this machine are allowed.
+    #[arg(long, value_delimiter = ',')]
+    ssh_cidr: Vec<Cidr>,
+    /// Seconds to wait for each instance to launch and be running, also used when terminating
+    /// instances and deleting resources.
+    #[arg(long)]
+    launch_timeout: Option<u64>,
+    /// Seconds to wait to connect SSH to each instance, including waiting for it to print its host
+    /// keys.
+    #[arg(long)]
+    ssh_timeout: Option<u64>,
+    /// Seconds to wait for each transfer of `--path` or artifacts.
     #[arg(long)]
-    timeout: Option<u64>,
-    /// The command to run on the instance.
+    transfer_timeout: Option<u64>,
+    /// Seconds each step may run for, overrides the timeout of the job.
+    #[arg(long, alias = "timeout")]
+    command_timeout: Option<u64>,
+    /// Seconds the whole run may take, after which it stops and everything created is deleted.
+    #[arg(long)]
+    deadline: Option<u64>,
+    /// The command to run on the instance, overrides the command of the job.
     #[arg(long)]
     command: Option<String>,
-    /// The size in GB of each EBS volume to attach to each instance.
+    /// The size in GB of each EBS volume to attach to each instance, overrides the size of each
+    /// target.
     #[arg(long)]
     size: Option<VolumeSize>,
-    /// The EC2 instance type.
+    /// The EC2 instance types, comma separated or repeated (e.g. `t2.medium,t4g.medium`).
+    ///
+    /// Each instance type is paired with the AMI at the same position in `--ami`. When given the
+    /// targets of the configuration file aren't used.
+    #[arg(long, value_delimiter = ',')]
+    instance: Vec<InstanceType>,
+    /// The EC2 AMIs, comma separated or repeated.
+    ///
+    /// Each is an AMI id, `ssm:<parameter>` for the AMI id held by an SSM parameter (e.g.
+    /// `ssm:/aws/service/canonical/ubuntu/server/22.04/stable/current/amd64/hvm/ebs-gp2/ami-id`),
+    /// `name:[<owner>:]<pattern>` for the latest AMI with a name matching the pattern, owned by
+    /// this account by default (e.g. `name:099720109477:ubuntu/images/*22.04*`), or one of the
+    /// aliases `ubuntu-20.04`, `ubuntu-22.04`, `ubuntu-24.04`, `debian-11`, `debian-12`, `al2` and
+    /// `al2023` for the latest AMI of the distribution. Name patterns and aliases are resolved for
+    /// the architecture of the instance type.
+    #[arg(long, value_delimiter = ',')]
+    ami: Vec<String>,
+    /// The VPC to launch instances into, by default the default VPC of the region.
+    ///
+    /// A subnet of the VPC is selected for each instance, the available subnet with the most free
+    /// addresses in `--availability-zone` and with every `--subnet-tag`.
     #[arg(long)]
-    instance: InstanceType,
-    /// The EC2 AMI.
+    vpc_id: Option<String>,
+    /// Only select subnets of `--vpc-id` with this tag, as `<key>=<value>`, may be repeated.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_tag, requires = "vpc_id")]
+    subnet_tag: Vec<(String, String)>,
+    /// The subnet to launch instances into, the security group is created in its VPC.
+    #[arg(long, conflicts_with_all = ["vpc_id", "availability_zone"])]
+    subnet_id: Option<String>,
+    /// The availability zone to launch instances into.
     #[arg(long)]
-    ami: String,
-}
-
-type SdkResponse = http::response::Response<aws_smithy_http::body::SdkBody>;
-type SdkError<E> = aws_smithy_http::result::SdkError<E, SdkResponse>;
-
-#[derive(Debug, thiserror::Error)]
-enum MainError {
-    #[error("Failed to create key pair: {0}")]
-    CreateKeyPair(SdkError<aws_sdk_ec2::operation::create_key_pair::CreateKeyPairError>),
-    #[error("Created key pair missing key material.")]
-    CreateKeyPairMaterial,
-    #[error("Failed to create security group: {0}")]
-    CreateSecurityGroup(
-        SdkError<aws_sdk_ec2::operation::create_security_group::CreateSecurityGroupError>,
-    ),
-    #[error("Created security group missing id.")]
-    CreateSecurityGroupId,
-    #[error("Failed to create security group ingress rule: {0}")]
-    AuthorizeSecurityGroupIngress(SdkError<aws_sdk_ec2::operation::authorize_security_group_ingress::AuthorizeSecurityGroupIngressError>),
-    #[error("Failed to run instances: {0}")]
-    RunInstances(SdkError<aws_sdk_ec2::operation::run_instances::RunInstancesError>),
-    #[error("Missing instance id from run instances.")]
-    RunInstancesInstanceId,
-    #[error("Instance failed to enter running state within timeout.")]
-    StartupTimeout,
-    #[error("Failed to describe instance status: {0}")]
-    DescribeInstanceStatus(SdkError<aws_sdk_ec2::operation::describe_instance_status::DescribeInstanceStatusError>),
-    #[error("Missing state from describe instance status.")]
-    DescribeInstanceStatusState,
-    #[error("Failed to describe instances: {0}")]
-    DescribeInstances(SdkError<aws_sdk_ec2::operation::describe_instances::DescribeInstancesError>),
-    #[error("Missing public ip address from describe instances.")]
-    DescribeInstancesPublicIpAddress,
-    #[error("Failed to parse public ip address: {0}")]
-    PublicIpParse(std::net::AddrParseError),
-    #[error("Failed to connect TCP stream: {0}")]
-    TcpStreamConnect(std::io::Error),
-    #[error("Failed to create SSH session: {0}")]
-    SshSession(ssh2::Error),
-    #[error("Failed SSH handshake: {0}")]
-    SshHandshake(std::io::Error),
-    #[error("Timed out attempting SSH handshake.")]
-    SshHandshakeTimeout,
-    #[error("Failed to setup SSH auth: {0}")]
-    SshAuthSetup(std::io::Error),
-    #[error("Failed SSH auth.")]
-    SshAuthFailed,
-    #[error("Failed to read directory: {0}")]
-    ReadDir(std::io::Error),
-    #[error("Failed to read entry: {0}")]
-    ReadEntry(std::io::Error),
-    #[error("Failed to read file type: {0}")]
-    ReadFileType(std::io::Error),
-    #[error("Failed to append directory to source archive: {0}")]
-    AppendDir(std::io::Error),
-    #[error("Failed to append file to source archive: {0}")]
-    AppendFile(std::io::Error),
-    #[error("Failed to complete archive: {0}")]
-    CompleteArchive(std::io::Error),
-    #[error("Failed to start scp: {0}")]
-    ScpSend(std::io::Error),
-    #[error("Failed to write to scp: {0}")]
-    ScpWrite(std::io::Error),
-    #[error("Failed to send eof to scp: {0}")]
-    ScpSendEof(ssh2::Error),
-    #[error("Failed to wait on eof on scp: {0}")]
-    ScpWaitEof(std::io::Error),
-    #[error("Timed out waiting for scp eof.")]
-    ScpEndOfFileTimeout,
-    #[error("Failed to close scp: {0}")]
-    ScpClose(std::io::Error),
-    #[error("Failed to wait on close on scp: {0}")]
-    ScpWaitClose(std::io::Error),
-    #[error("Failed to exec command: {0}")]
-    Exec(ExecError),
-    #[error("Decompress timed out.")]
-    DecompressTimeout,
-    #[error("Failed to decompress archive: {0}")]
-    DecompressFailed(i32),
-    #[error("Failed to terminate instances: {0}")]
-    TerminateInstances(SdkError<aws_sdk_ec2::operation::terminate_instances::TerminateInstancesError>),
-    #[error("Failed to delete key pair: {0}")]
-    DeleteKeyPair(SdkError<aws_sdk_ec2::operation::delete_key_pair::DeleteKeyPairError>),
-    // #[error("Failed to delete network interface: {0}")]
-    // DeleteNetworkInterface(SdkError<aws_sdk_ec2::operation::delete_network_interface::DeleteNetworkInterfaceError>),
-    // #[error("Failed to delete security group: {0}")]
-    // DeleteSecurityGroup(SdkError<aws_sdk_ec2::operation::delete_security_group::DeleteSecurityGroupError>),
+    availability_zone: Option<String>,
+    /// The name of an existing placement group to launch instances into.
+    #[arg(long)]
+    placement_group: Option<String>,
+    /// Which IP address of each instance to connect to over SSH: `public`, `private` or `auto`.
+    ///
+    /// `auto` uses the public IP address if the instance has one, else the private one. With
+    /// `private` instances launched into a subnet aren't given a public IP address.
+    #[arg(long, default_value_t)]
+    connect: Connect,
+    /// Connect to each instance through an SSH server, as `<user>@<host>[:<port>]`, e.g. a bastion
+    /// in the VPC.
+    ///
+    /// Its host key is verified against `~/.ssh/known_hosts` and by default the SSH agent is used
+    /// to authenticate.
+    #[arg(long, value_name = "USER@HOST[:PORT]")]
+    jump_host: Option<JumpHost>,
+    /// A private key file to authenticate with the jump host.
+    #[arg(long, requires = "jump_host")]
+    jump_identity: Option<PathBuf>,
+    /// How to run commands on each instance: `ssh` or `ssm`.
+    ///
+    /// `ssm` sends commands through the SSM agent as root, so no SSH ingress is needed. The AMI
+    /// must have the agent and `--instance-profile` must allow it to register with SSM.
+    #[arg(long, default_value_t)]
+    transport: TransportKind,
+    /// The name of an IAM instance profile to launch instances with.
+    #[arg(long)]
+    instance_profile: Option<String>,
+    /// An S3 bucket to stage files and command output in with `--transport ssm`.
+    ///
+    /// Without one files are copied 16 KiB per command and output beyond 24,000 characters of
+    /// stdout or 8,000 of stderr is lost. The instance profile must allow `s3:PutObject` to the
+    /// bucket for the full output to be uploaded.
+    #[arg(long)]
+    ssm_bucket: Option<String>,
+    /// Launch spot instances rather than on-demand instances.
+    #[arg(long)]
+    spot: bool,
+    /// The maximum price per hour in USD of each spot instance, by default the on-demand price.
+    #[arg(long, requires = "spot")]
+    spot_max_price: Option<String>,
+    /// When there is no spot capacity, try each availability zone of the region in turn (with
+    /// `--vpc-id`, each zone the VPC has a matching subnet in).
+    #[arg(long, requires = "spot")]
+    spot_other_zones: bool,
+    /// When there is no spot capacity, or a spot instance is interrupted, use an on-demand instance
+    /// instead.
+    #[arg(long, requires = "spot")]
+    spot_fallback_on_demand: bool,
+    /// Seconds to keep each instance running after the command finishes, so you can SSH in and
+    /// debug.
+    #[arg(long)]
+    linger: Option<u64>,
+    /// Only linger when the command fails.
+    #[arg(long, requires = "linger")]
+    linger_on_failure: bool,
+    /// The user to login as over SSH.
+    ///
+    /// By default this is the user of each target, or inferred from the owner and name of its
+    /// AMI.
+    #[arg(long)]
+    user: Option<String>,
+    /// Don't verify the SSH host key of each instance against the keys it prints to its console.
+    #[arg(long)]
+    insecure_skip_host_key_check: bool,
+    /// Files to download from each instance after the command finishes, as
+    /// `<remote-glob>:<local-dir>`, may be repeated.
+    ///
+    /// The glob is expanded by the remote shell in the directory the command is run in. With
+    /// multiple instances the files from each are put in `<local-dir>/<instance>/<ami>`. When given
+    /// the artifacts of the job aren't used.
+    #[arg(long, value_name = "REMOTE_GLOB:LOCAL_DIR")]
+    artifact: Vec<Artifact>,
 }
 
-#[derive(Debug, thiserror::Error)]
-enum ExecError {
-    #[error("Failed to create channel: {0}")]
-    Channel(std::io::Error),
-    #[error("Timed out creating channel.")]
-    ChannelTimeout,
-    #[error("Failed exec: {0}")]
-    Exec(std::io::Error),
-    #[error("Timed out running exec.")]
-    ExecTimeout,
-    #[error("Failed to read stdout: {0}")]
-    Stdout(std::io::Error),
-    #[error("Failed to read stderr: {0}")]
-    Stderr(std::io::Error),
-    #[error("Failed to close channel: {0}")]
-    Close(std::io::Error),
-    #[error("Failed to get exit code: {0}")]
-    Exit(ssh2::Error),
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Finds and deletes instances, security groups and key pairs created by this tool which have
+    /// been leaked.
+    Gc {
+        /// Only delete resources created more than this many seconds ago.
+        #[arg(long, default_value_t = DEFAULT_GC_OLDER_THAN_SECS)]
+        older_than: u64,
+        /// List the resources which would be deleted without deleting them.
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    match main_exec().await {
-        Err(err) => {
-            eprintln!("Error: {err:?}");
-            ExitCode::FAILURE
-        }
-        Ok(None) => {
-            eprintln!("Command timeout");
-            ExitCode::FAILURE
-        }
-        Ok(Some(code)) => ExitCode::from(u8::try_from(code).unwrap()),
-    }
-}
-
-async fn main_exec() -> Result<Option<i32>, MainError> {
-    #[allow(clippy::enum_glob_use)]
-    use MainError::*;
-
     tracing_subscriber::fmt().init();
 
-    let (key_name, timeout, security_group_name, instance_type, ami, command, path, size) =
-        parse_args();
-
-    info!("Loading aws config");
-    let config = aws_config::load_from_env().await;
-    let client = ec2::Client::new(&config);
-
-    info!("Creating SSH key pair");
-    let builder = client.create_key_pair().key_name(key_name.clone());
-    let create_key_pair_response = builder.send().await.map_err(CreateKeyPair)?;
-
-    // Private key
-    let key_material = create_key_pair_response
-        .key_material
-        .ok_or(CreateKeyPairMaterial)?;
-
-    info!("Creating security groups");
-    // The default settings prevent SSH working.
-    let builder = client
-        .create_security_group()
-        .set_group_name(Some(security_group_name.clone()))
-        .set_description(Some(String::from(SECURITY_GROUP_DESCRIPTION)));
-    let create_security_group_response = builder.send().await.map_err(CreateSecurityGroup)?;
-    let security_group_id = create_security_group_response
-        .group_id
-        .ok_or(CreateSecurityGroupId)?;
-
-    // Set inbound rule (the default outbound rule is fine).
-    info!("Setting ingress security group rule");
-    let builder = client
-        .authorize_security_group_ingress()
-        .set_group_id(Some(security_group_id.clone()))
-        .set_ip_protocol(Some(String::from("tcp")))
-        .set_from_port(Some(22))
-        .set_to_port(Some(22))
-        .set_cidr_ip(Some(String::from("0.0.0.0/0")));
-    builder
-        .send()
-        .await
-        .map_err(AuthorizeSecurityGroupIngress)?;
-
-    let code = run_instance(
-        &client,
-        &key_name,
-        &security_group_name,
-        &timeout,
-        &path,
-        &key_material,
-        &command,
-        &size,
-        &instance_type,
-        &ami,
-    )
-    .await?;
-    info!("code: {code:?}");
-
-    info!("Deleting key pair");
-    let builder = client
-        .delete_key_pair()
-        .set_key_name(Some(key_name.clone()));
-    builder.send().await.map_err(DeleteKeyPair)?;
-
-    // TODO: Delete the created security group.
-    // See the below commented out code.
-
-    // info!("Deleting network interface");
-    // let network_interface_id = todo!();
-    // let builder = client.delete_network_interface().set_network_interface_id(input);
-    // builder.send().await.map_err(DeleteNetworkInterface)?;
-
-    // info!("Sleeping for {DELETE_SECURITY_GROUP_BUFFER:?}.");
-    // sleep(DELETE_SECURITY_GROUP_BUFFER);
-
-    // info!("Deleting security group");
-    // let builder = client
-    //     .delete_security_group()
-    //     .set_group_id(Some(security_group_id.clone()));
-    // builder.send().await.map_err(DeleteSecurityGroup)?;
-
-    Ok(code)
-}
-
-fn parse_args() -> (
-    String,
-    Duration,
-    String,
-    InstanceType,
-    String,
-    String,
-    Option<String>,
-    VolumeSize,
-) {
     info!("Parsing command line arguments");
     let args = Args::parse();
 
-    let key_name = args
-        .key_name
-        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
-    let timeout = Duration::from_secs(args.timeout.unwrap_or(DEFAULT_COMMAND_TIMEOUT_SECS));
-    let security_group_name = args
-        .security_group_name
-        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
-
-    let command = args
-        .command
-        .unwrap_or_else(|| String::from(DEFAULT_COMMAND));
-    let path = args.path;
-    let size = args.size.unwrap_or(DEFAULT_SIZE);
-
-    (
-        key_name,
-        timeout,
-        security_group_name,
-        args.instance,
-        args.ami,
-        command,
-        path,
-        size,
-    )
-}
-
-#[allow(clippy::too_many_arguments)]
-async fn run_instance(
-    client: &ec2::Client,
-    key_name: &str,
-    security_group_id: &str,
-    timeout: &Duration,
-    path: &Option<String>,
-    private_key: &str,
-    command: &str,
-    size: &VolumeSize,
-    instance: &InstanceType,
-    ami: &str,
-) -> Result<Option<i32>, MainError> {
-    #[allow(clippy::enum_glob_use)]
-    use MainError::*;
-
-    // Launches instance
-    let (public_ip_address, instance_id) = launch_instance(
-        client,
-        instance,
-        ami,
-        key_name,
-        security_group_id,
-        timeout,
-        size,
-    )
-    .await?;
-
-    let ssh = create_ssh(&public_ip_address, timeout, private_key)?;
-    let remote_path = format!("/tmp/{}", uuid::Uuid::new_v4());
-
-    // Transfers source code
-    if let Some(path) = path {
-        transfer_source(path, &remote_path, &ssh, timeout).await?;
+    if let Some(Subcommand::Gc {
+        older_than,
+        dry_run,
+    }) = args.subcommand
+    {
+        info!("Loading aws config");
+        let client = ec2::Client::new(&aws_config::load_from_env().await);
+        let timeout = Duration::from_secs(DEFAULT_LAUNCH_TIMEOUT_SECS);
+        return match gc(&client, &timeout, Duration::from_secs(older_than), dry_run).await {
+            Ok(resources) => {
+                if dry_run {
+                    for resource in resources {
+                        println!("{resource}");
+                    }
+                }
+                ExitCode::SUCCESS
+            }
+            Err(err) => {
+                eprintln!("Error: {err:?}");
+                ExitCode::FAILURE
+            }
+        };
     }
 
-    let code = exec(&ssh, command, timeout).map_err(Exec)?;
-
-    info!("Sleeping for {RUN_BUFFER:?}.");
-    sleep(RUN_BUFFER);
-
-    info!("Terminate instances");
-    let builder = client
-        .terminate_instances()
-        .set_instance_ids(Some(vec![instance_id]));
-    builder.send().await.map_err(TerminateInstances)?;
-
-    Ok(code)
-}
-
-fn create_ssh(
-    public_ip_address: &str,
-    timeout: &Duration,
-    private_key: &str,
-) -> Result<ssh2::Session, MainError> {
-    #[allow(clippy::enum_glob_use)]
-    use MainError::*;
-
-    // I have no idea why this is needed but for some reason we need to wait for ssh to work, I
-    // don't know what is being waited on, this should poll.
-    info!("Sleeping for {SSH_STARTUP_BUFFER:?}.");
-    sleep(SSH_STARTUP_BUFFER);
-
-    info!("Connecting SSH");
-    let ipv4_address = std::net::Ipv4Addr::from_str(public_ip_address).map_err(PublicIpParse)?;
-    let socket_address =
-        std::net::SocketAddr::V4(std::net::SocketAddrV4::new(ipv4_address, EC2_SSH_PORT));
-    info!("socket_address: {socket_address}");
-    let tcp = std::net::TcpStream::connect(socket_address).map_err(TcpStreamConnect)?;
-    tcp.set_nonblocking(true).unwrap();
-    let mut ssh = ssh2::Session::new().map_err(SshSession)?;
-    ssh.set_tcp_stream(tcp);
-    ssh.set_blocking(false);
+    let print_config = args.print_config;
+    let settings = parse_args(args);
+    if print_config {
+        print!("{}", toml::to_string_pretty(&settings).unwrap());
+        return ExitCode::SUCCESS;
+    }
 
-    // SSH handshake
-    let start = Instant::now();
-    info!("SSH handshake");
-    loop {
-        match ssh.handshake().map_err(std::io::Error::from) {
-            Ok(()) => break,
-            Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                if start.elapsed() > *timeout {
-                    return Err(SshHandshakeTimeout);
-                }
-            }
-            Err(err) => return Err(SshHandshake(err)),
-        }
+    // Targets in different regions need different clients, so are run by separate runners.
+    info!("Loading aws config");
+    let mut runners = Vec::new();
+    for (region, config) in run_configs(settings) {
+        let loader = aws_config::from_env();
+        let loader = match region {
+            Some(region) => loader.region(ec2::config::Region::new(region)),
+            None => loader,
+        };
+        let sdk_config = loader.load().await;
+        // Also used to resolve AMIs from SSM parameters and aliases.
+        let runner = Runner::new(ec2::Client::new(&sdk_config), config)
+            .ssm_client(aws_sdk_ssm::Client::new(&sdk_config))
+            .s3_client(aws_sdk_s3::Client::new(&sdk_config));
+        runners.push(runner);
     }
 
-    // SSH authorize
-    let start = Instant::now();
-    info!("SSH authorize");
-    loop {
-        match ssh
-            .userauth_pubkey_memory("ubuntu", None, private_key, None)
-            .map_err(std::io::Error::from)
-        {
-            Ok(()) => break,
-            Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                if start.elapsed() > *timeout {
-                    return Err(SshHandshakeTimeout);
-                }
-            }
-            Err(err) => return Err(SshAuthSetup(err)),
+    let shutdown = async {
+        let signal = shutdown_signal().await;
+        error!("Received {signal}, cleaning up");
+        signal
+    };
+    let (end, cleaned) = run_all(&runners, shutdown).await;
+
+    // The signal exit code takes precedence over a failure to delete resources.
+    let signalled = matches!(end, RunEnd::Shutdown(_));
+    let code = match end {
+        RunEnd::Shutdown(signal) => ExitCode::from(128 + signal.number()),
+        RunEnd::Finished(Err(err)) => {
+            eprintln!("Error: {err}");
+            ExitCode::from(INFRASTRUCTURE_EXIT_CODE)
         }
+        RunEnd::Finished(Ok(None)) => {
+            eprintln!("Error: command timed out");
+            ExitCode::from(TIMEOUT_EXIT_CODE)
+        }
+        RunEnd::Finished(Ok(Some(code))) => {
+            ExitCode::from(u8::try_from(code).unwrap_or_else(|_| {
+                // Only codes from a non-POSIX shell could be outside `0..=255`.
+                error!("Exit code {code} is out of range, exiting with 255");
+                u8::MAX
+            }))
+        }
+    };
+    if !cleaned {
+        eprintln!("Error: failed to delete some resources, run `aws-ec2 gc` to delete them");
     }
-
-    if !ssh.authenticated() {
-        return Err(SshAuthFailed);
+    if cleaned || signalled {
+        code
+    } else {
+        ExitCode::from(INFRASTRUCTURE_EXIT_CODE)
     }
-    Ok(ssh)
 }
 
-/// Waits until the given instance is in the running state.
-async fn wait_until_running(
-    client: &ec2::Client,
-    timeout: &Duration,
-    instance_id: &str,
-) -> Result<(), MainError> {
-    #[allow(clippy::enum_glob_use)]
-    use MainError::*;
+/// A signal requesting the run be stopped.
+#[derive(Debug, Clone, Copy)]
+enum ShutdownSignal {
+    Interrupt,
+    Terminate,
+}
 
-    let start = Instant::now();
-    info!("Waiting for instance to enter the `running` state");
-    loop {
-        if start.elapsed() > *timeout {
-            return Err(StartupTimeout);
+impl ShutdownSignal {
+    /// The signal number, used to form the conventional `128 + n` exit code.
+    fn number(self) -> u8 {
+        match self {
+            Self::Interrupt => 2,
+            Self::Terminate => 15,
         }
+    }
+}
 
-        sleep(INSTANCE_POLL_STATE_SLEEP);
-
-        let builder = client
-            .describe_instance_status()
-            .set_instance_ids(Some(vec![instance_id.to_string()]))
-            // By default this doesn't return descriptions for instances outside the `running`
-            // state, in our case we want these description, so we set this parameter.
-            .set_include_all_instances(Some(true));
-        let describe_instance_status_response =
-            builder.send().await.map_err(DescribeInstanceStatus)?;
-
-        let Some(
-            [ec2::types::InstanceStatus {
-                instance_state:
-                    Some(ec2::types::InstanceState {
-                        name: Some(state), ..
-                    }),
-                ..
-            }],
-        ) = describe_instance_status_response
-            .instance_statuses
-            .as_deref()
-        else {
-            return Err(DescribeInstanceStatusState);
-        };
-        if *state == ec2::types::InstanceStateName::Running {
-            return Ok(());
+impl std::fmt::Display for ShutdownSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Interrupt => write!(f, "SIGINT"),
+            Self::Terminate => write!(f, "SIGTERM"),
         }
     }
 }
 
-/// Using the default as recommend here
-/// <https://docs.rs/aws-sdk-ec2/0.33.0/aws_sdk_ec2/types/builders/struct.BlockDeviceMappingBuilder.html#method.set_device_name>
-/// and here <https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/device_naming.html>.
-const DEFAULT_BLOCK_DEVICE_NAME: &str = "/dev/sdh";
-
-/// Launches an EC2 instance and returns the public ip address.
-async fn launch_instance(
-    client: &ec2::Client,
-    instance_type: &InstanceType,
-    ami: &str,
-    key_name: &str,
-    security_group_id: &str,
-    timeout: &Duration,
-    size: &VolumeSize,
-) -> Result<(String, String), MainError> {
-    #[allow(clippy::enum_glob_use)]
-    use MainError::*;
+/// Waits for SIGINT (Ctrl-C) or SIGTERM.
+async fn shutdown_signal() -> ShutdownSignal {
+    let mut terminate =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => ShutdownSignal::Interrupt,
+        _ = terminate.recv() => ShutdownSignal::Terminate,
+    }
+}
 
-    info!("Launching instances");
-    let builder = client
-        .run_instances()
-        .set_instance_type(Some(instance_type.clone()))
-        .set_image_id(Some(String::from(ami)))
-        .set_max_count(Some(1))
-        .set_min_count(Some(1))
-        .set_key_name(Some(String::from(key_name)))
-        .set_security_group_ids(Some(vec![String::from(security_group_id)]))
-        .set_block_device_mappings(Some(vec![aws_sdk_ec2::types::BlockDeviceMapping::builder(
-        )
-        .ebs(
-            aws_sdk_ec2::types::EbsBlockDevice::builder()
-                .set_volume_size(Some(i32::from(*size)))
-                .build(),
-        )
-        .set_device_name(Some(String::from(DEFAULT_BLOCK_DEVICE_NAME)))
-        .build()]));
-    let run_instances_response = builder.send().await.map_err(RunInstances)?;
+/// Merges the configuration file and the command line arguments, exiting if they are invalid.
+fn parse_args(args: Args) -> Settings {
+    use clap::error::ErrorKind;
+    let exit =
+        |kind: ErrorKind, message: String| -> ! { Args::command().error(kind, message).exit() };
+
+    if args.instance.len() != args.ami.len() {
+        exit(
+            ErrorKind::WrongNumberOfValues,
+            format!(
+                "`--instance` has {} values but `--ami` has {}, each instance type must be paired \
+                 with an AMI",
+                args.instance.len(),
+                args.ami.len()
+            ),
+        );
+    }
+    if args.transport == TransportKind::Ssm && args.jump_host.is_some() {
+        exit(
+            ErrorKind::ArgumentConflict,
+            String::from("`--jump-host` can't be used with `--transport ssm`"),
+        );
+    }
 
-    let Some(
-        [aws_sdk_ec2::types::Instance {
-            instance_id: Some(instance_id),
-            ..
-        }],
-    ) = &run_instances_response.instances.as_deref()
-    else {
-        return Err(RunInstancesInstanceId);
+    let (config, file) = match ConfigFile::load(args.config.as_deref()) {
+        Ok(Some((path, file))) => (Some(path), file),
+        Ok(None) => (None, ConfigFile::default()),
+        Err(err) => exit(ErrorKind::Io, err),
+    };
+    let (job, job_config) = match file.job(args.job.as_deref()) {
+        Ok(Some((name, job))) => (Some(String::from(name)), job.clone()),
+        Ok(None) => (None, config::JobConfig::default()),
+        Err(err) => exit(ErrorKind::InvalidValue, err),
     };
 
-    // The instance is not immediately assigned a public IP address so we need to wait.
-    wait_until_running(client, timeout, instance_id.as_str()).await?;
+    let targets =
+        target_settings(&args, &file, &job_config).unwrap_or_else(|(kind, err)| exit(kind, err));
 
-    info!("Getting running instance description");
-    let builder = client
-        .describe_instances()
-        .set_instance_ids(Some(vec![instance_id.clone()]));
-    let describe_instances_response = builder.send().await.map_err(DescribeInstances)?;
-    let public_ip_address = match describe_instances_response.reservations.as_deref() {
-        Some(
-            [ec2::types::Reservation {
-                instances: Some(instance_descriptions),
-                ..
-            }],
-        ) if let [ec2::types::Instance {
-            public_ip_address: Some(public_ip_address),
-            ..
-        }] = instance_descriptions.as_slice() =>
-        {
-            public_ip_address
-        }
-        _ => return Err(DescribeInstancesPublicIpAddress),
+    let steps = job_config.all_steps();
+    let command = match args.command.or(job_config.command) {
+        None if steps.is_empty() => Some(String::from(DEFAULT_COMMAND)),
+        command => command,
     };
 
-    Ok((public_ip_address.clone(), instance_id.clone()))
+    Settings {
+        config,
+        job,
+        command,
+        launch_timeout: args.launch_timeout.unwrap_or(DEFAULT_LAUNCH_TIMEOUT_SECS),
+        ssh_timeout: args.ssh_timeout.unwrap_or(DEFAULT_SSH_TIMEOUT_SECS),
+        transfer_timeout: args
+            .transfer_timeout
+            .unwrap_or(DEFAULT_TRANSFER_TIMEOUT_SECS),
+        command_timeout: args
+            .command_timeout
+            .or(job_config.timeout)
+            .unwrap_or(DEFAULT_COMMAND_TIMEOUT_SECS),
+        deadline: args.deadline,
+        artifacts: if args.artifact.is_empty() {
+            job_config.artifacts
+        } else {
+            args.artifact
+        },
+        path: args.path,
+        include: args.include,
+        exclude: args.exclude,
+        remote_dir: args.remote_dir,
+        key_name: args.key_name,
+        security_group_name: args.security_group_name,
+        security_group_id: args.security_group_id,
+        ssh_cidr: args.ssh_cidr,
+        linger: args.linger,
+        linger_on_failure: args.linger_on_failure,
+        insecure_skip_host_key_check: args.insecure_skip_host_key_check,
+        connect: args.connect,
+        jump_host: args.jump_host,
+        jump_identity: args.jump_identity,
+        transport: args.transport,
+        instance_profile: args.instance_profile,
+        ssm_bucket: args.ssm_bucket,
+        network: NetworkSettings {
+            vpc_id: args.vpc_id,
+            subnet_tags: args.subnet_tag.into_iter().collect(),
+            subnet_id: args.subnet_id,
+            availability_zone: args.availability_zone,
+            placement_group: args.placement_group,
+        },
+        spot: args.spot.then_some(SpotSettings {
+            max_price: args.spot_max_price,
+            other_zones: args.spot_other_zones,
+            fallback_on_demand: args.spot_fallback_on_demand,
+        }),
+        steps,
+        targets,
+    }
 }
 
-async fn get_archive_data(dir: &str) -> Result<&[u8], MainError> {
-    #[allow(clippy::enum_glob_use)]
-    use MainError::*;
-
-    static ARCHIVE: tokio::sync::OnceCell<Vec<u8>> = tokio::sync::OnceCell::const_new();
-    let init = async || -> Result<Vec<u8>, MainError> {
-        let mut tar_gz = Vec::new();
-        let enc = GzEncoder::new(&mut tar_gz, Compression::default());
-        let mut tar = tar::Builder::new(enc);
-        let ignore: [&str; 3] = ["/.git", "/.gitignore", "/target"];
-        info!("Reading local directory: {dir:?}");
-        let paths = std::fs::read_dir(dir).map_err(ReadDir)?;
-        for path in paths {
-            let entry = path.map_err(ReadEntry)?;
-            let path_buf = entry.path();
-            let name = path_buf.as_path().file_name().unwrap().to_str().unwrap();
-            let path_string = path_buf.display().to_string();
-            info!("Inspecting: {path_string:?}");
-            if !ignore.contains(&path_string.as_str()) {
-                let file_type = entry.file_type().map_err(ReadFileType)?;
-                if file_type.is_dir() {
-                    tar.append_dir_all(name, &path_string).map_err(AppendDir)?;
-                } else if file_type.is_file() {
-                    tar.append_path_with_name(path_string, name)
-                        .map_err(AppendFile)?;
-                }
-            }
-        }
-        tar.into_inner().map_err(CompleteArchive)?;
-
-        Ok(tar_gz)
+/// The targets to run on, from the command line arguments if given else the configuration file.
+fn target_settings(
+    args: &Args,
+    file: &ConfigFile,
+    job_config: &config::JobConfig,
+) -> Result<Vec<TargetSettings>, (clap::error::ErrorKind, String)> {
+    use clap::error::ErrorKind;
+
+    let targets = if args.instance.is_empty() {
+        let targets = file
+            .targets(Some(job_config))
+            .map_err(|err| (ErrorKind::InvalidValue, err))?;
+        targets
+            .into_iter()
+            .map(|(name, target)| TargetSettings {
+                name: Some(name),
+                instance: target.instance,
+                ami: target.ami,
+                size: args.size.or(target.size).unwrap_or(DEFAULT_SIZE),
+                user: args.user.clone().or(target.user),
+                region: target.region,
+            })
+            .collect::<Vec<_>>()
+    } else {
+        args.instance
+            .iter()
+            .zip(&args.ami)
+            .map(|(instance, ami)| TargetSettings {
+                name: None,
+                instance: String::from(instance.as_str()),
+                ami: ami.clone(),
+                size: args.size.unwrap_or(DEFAULT_SIZE),
+                user: args.user.clone(),
+                region: None,
+            })
+            .collect()
     };
-    ARCHIVE.get_or_try_init(init).await.map(Vec::as_slice)
+    if targets.is_empty() {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            String::from(
+                "no targets, pass `--instance` and `--ami` or define targets in the configuration \
+                 file",
+            ),
+        ));
+    }
+    Ok(targets)
 }
 
-/// Transfers source files to the instance
-async fn transfer_source(
-    local_path: &str,
-    remote_path: &str,
-    ssh: &ssh2::Session,
-    timeout: &Duration,
-) -> Result<(), MainError> {
-    #[allow(clippy::enum_glob_use)]
-    use MainError::*;
-
-    // Get source code data when stored in an archive.
-    let data = get_archive_data(local_path).await.unwrap();
-
-    info!("Copying source");
-
-    // TODO What is the mode value of `0o644` doing here? I just copied it from the docs
-    // https://docs.rs/ssh2/latest/ssh2/#upload-a-file.
-    let start = Instant::now();
-    info!("scp send");
-    let mut channel = loop {
-        if start.elapsed() > *timeout {
-            return Err(SshHandshakeTimeout);
-        }
-
-        match ssh
-            .scp_send(Path::new(remote_path), 0o644, data.len() as u64, None)
-            .map_err(std::io::Error::from)
-        {
-            Ok(c) => break c,
-            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-            Err(err) => return Err(ScpSend(err)),
-        }
-    };
-
-    // Write archive to remote.
-    let mut n = 0;
-    loop {
-        if start.elapsed() > *timeout {
-            return Err(SshHandshakeTimeout);
-        }
-        n += match channel.write(&data[n..]) {
-            Ok(0) if n == data.len() => break,
-            Ok(c) => c,
-            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-            Err(err) => return Err(ScpWrite(err)),
-        };
+/// Parses a `<key>=<value>` tag.
+fn parse_tag(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((String::from(key), String::from(value))),
+        _ => Err(format!("{s:?} is not of the form `<key>=<value>`")),
     }
+}
 
-    // Wait send ending for write of archive to remote.
-    channel.send_eof().map_err(ScpSendEof)?;
-
-    // Wait for end of file
-    let start = Instant::now();
-    info!("Wait for scp end of file");
-    loop {
-        if start.elapsed() > *timeout {
-            return Err(ScpEndOfFileTimeout);
-        }
-
-        match channel.wait_eof().map_err(std::io::Error::from) {
-            Ok(()) => break,
-            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-            Err(err) => return Err(ScpWaitEof(err)),
-        }
+/// Builds where instances are launched from the effective configuration.
+fn network(settings: NetworkSettings) -> Network {
+    let mut network = Network::new();
+    if let Some(vpc_id) = settings.vpc_id {
+        network = network.vpc_id(vpc_id);
     }
-
-    info!("Closing scp");
-    loop {
-        if start.elapsed() > *timeout {
-            return Err(ScpEndOfFileTimeout);
-        }
-
-        match channel.close().map_err(std::io::Error::from) {
-            Ok(()) => break,
-            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-            Err(err) => return Err(ScpClose(err)),
-        }
+    for (key, value) in settings.subnet_tags {
+        network = network.subnet_tag(key, value);
     }
-
-    info!("Waiting close scp");
-    loop {
-        if start.elapsed() > *timeout {
-            return Err(ScpEndOfFileTimeout);
-        }
-
-        match channel.wait_close().map_err(std::io::Error::from) {
-            Ok(()) => break,
-            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-            Err(err) => return Err(ScpWaitClose(err)),
-        }
+    if let Some(subnet_id) = settings.subnet_id {
+        network = network.subnet_id(subnet_id);
     }
-
-    info!("Decompressing source");
-
-    let Some(code) = exec(ssh, &format!("tar -xf {remote_path}"), timeout).map_err(Exec)? else {
-        return Err(DecompressTimeout);
-    };
-    if code != 0 {
-        return Err(DecompressFailed(code));
+    if let Some(availability_zone) = settings.availability_zone {
+        network = network.availability_zone(availability_zone);
+    }
+    if let Some(placement_group) = settings.placement_group {
+        network = network.placement_group(placement_group);
     }
-    Ok(())
+    network
 }
 
-fn exec(
-    session: &ssh2::Session,
-    command: &str,
-    timeout: &Duration,
-) -> Result<Option<i32>, ExecError> {
-    #[allow(clippy::enum_glob_use)]
-    use ExecError::*;
-
-    let start = std::time::Instant::now();
-
-    // Opening channel
-    info!("Opening channel");
-    let mut channel = loop {
-        if start.elapsed() > *timeout {
-            return Err(ChannelTimeout);
+/// Builds a run configuration for each region the targets are in, `None` being the region from
+/// the environment.
+fn run_configs(settings: Settings) -> Vec<(Option<String>, RunConfig)> {
+    let mut config = RunConfig::new()
+        .launch_timeout(Duration::from_secs(settings.launch_timeout))
+        .ssh_timeout(Duration::from_secs(settings.ssh_timeout))
+        .transfer_timeout(Duration::from_secs(settings.transfer_timeout))
+        .command_timeout(Duration::from_secs(settings.command_timeout))
+        .verify_host_key(!settings.insecure_skip_host_key_check)
+        .connect(settings.connect);
+    if let Some(mut jump_host) = settings.jump_host {
+        if let Some(identity) = settings.jump_identity {
+            jump_host = jump_host.identity(identity);
         }
-
-        match session.channel_session().map_err(std::io::Error::from) {
-            Ok(c) => break c,
-            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-            Err(err) => return Err(Channel(err)),
+        config = config.jump_host(jump_host);
+    }
+    config = config.transport(settings.transport);
+    if let Some(instance_profile) = settings.instance_profile {
+        config = config.instance_profile(instance_profile);
+    }
+    if let Some(ssm_bucket) = settings.ssm_bucket {
+        config = config.ssm_bucket(ssm_bucket);
+    }
+    for step in settings.steps {
+        let mut run_step = Step::new(step.name, step.run)
+            .continue_on_error(step.continue_on_error)
+            .always(step.always);
+        if let Some(timeout) = step.timeout {
+            run_step = run_step.timeout(Duration::from_secs(timeout));
         }
-    };
-
-    // Get stderr stream
-    let mut stderr_stream = channel.stderr();
-
-    // Running exec
-    info!("Running exec: {command:?}");
-    loop {
-        if start.elapsed() > *timeout {
-            return Err(ExecTimeout);
+        config = config.step(run_step);
+    }
+    if let Some(command) = settings.command {
+        config = config.command(command);
+    }
+    if let Some(path) = settings.path {
+        let mut source = Source::new(path);
+        for glob in settings.include {
+            source = source.include(glob);
         }
-
-        match channel.exec(command).map_err(std::io::Error::from) {
-            Ok(()) => break,
-            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-            Err(err) => return Err(Exec(err)),
+        for glob in settings.exclude {
+            source = source.exclude(glob);
         }
-    }
-
-    // Stdout
-    // ---------------------------------------------------------------------------------------------
-    let timeout_stdout = *timeout;
-    let stdout_handle = std::thread::spawn(move || {
-        let mut stdout = std::io::stdout();
-        loop {
-            let mut buffer = [u8::default(); 1024];
-            let n = match channel.read(&mut buffer) {
-                Ok(0) => break,
-                Ok(n) => n,
-                Err(err) if err.kind() == WouldBlock => continue,
-                Err(err) => return Err(Stdout(err)),
-            };
-
-            stdout.write_all(&buffer[..n]).unwrap();
+        if let Some(remote_dir) = settings.remote_dir {
+            source = source.remote_dir(remote_dir);
         }
-
-        Ok(channel)
+        config = config.source(source);
+    }
+    if let Some(deadline) = settings.deadline {
+        config = config.deadline(Duration::from_secs(deadline));
+    }
+    if let Some(key_name) = settings.key_name {
+        config = config.key_name(key_name);
+    }
+    config = config.security_group(match settings.security_group_id {
+        Some(id) => SecurityGroup::Existing(id),
+        None => SecurityGroup::Create {
+            name: settings.security_group_name,
+            ssh_cidrs: settings.ssh_cidr,
+        },
     });
-
-    // Stderr
-    // ---------------------------------------------------------------------------------------------
-    let timeout_stderr = *timeout;
-    let stderr_handle = std::thread::spawn(move || {
-        let mut stderr = std::io::stderr();
-        loop {
-            if start.elapsed() > timeout_stderr {
-                break;
-            }
-
-            let mut buffer = [u8::default(); 1024];
-            let n = match stderr_stream.read(&mut buffer) {
-                Ok(0) => break,
-                Ok(n) => n,
-                Err(err) if err.kind() == WouldBlock => continue,
-                Err(err) => return Err(Stderr(err)),
-            };
-
-            stderr.write_all(&buffer[..n]).unwrap();
+    if let Some(linger) = settings.linger {
+        config = config.linger(
+            Linger::new(Duration::from_secs(linger)).on_failure_only(settings.linger_on_failure),
+        );
+    }
+    config = config.network(network(settings.network));
+    if let Some(spot_settings) = settings.spot {
+        let mut spot = Spot::new()
+            .other_zones(spot_settings.other_zones)
+            .on_demand_fallback(spot_settings.fallback_on_demand);
+        if let Some(max_price) = spot_settings.max_price {
+            spot = spot.max_price(max_price);
         }
-        Ok(())
-    });
-
-    // Wait
-    // ---------------------------------------------------------------------------------------------
-    let (stdout_result, stderr_result) = (stdout_handle.join(), stderr_handle.join());
-
-    let mut channel = stdout_result.unwrap()?;
-    info!("Joined stdout");
-    stderr_result.unwrap()?;
-    info!("Joined stderr");
-
-    // Exit
-    // ---------------------------------------------------------------------------------------------
-    info!("Waiting on close");
-    loop {
-        if start.elapsed() > timeout_stdout {
-            return Ok(None);
+        config = config.spot(spot);
+    }
+    for artifact in settings.artifacts {
+        config = config.artifact(artifact);
+    }
+    // Targets are split between runners by region, but the artifacts layout shouldn't depend on it.
+    config = config.separate_artifacts(settings.targets.len() > 1);
+
+    let mut configs: Vec<(Option<String>, RunConfig)> = Vec::new();
+    for target in settings.targets {
+        let mut run_target =
+            Target::new(InstanceType::from(target.instance.as_str()), target.ami).size(target.size);
+        if let Some(user) = target.user {
+            run_target = run_target.user(user);
         }
-
-        match channel.wait_close().map_err(std::io::Error::from) {
-            Ok(()) => return Ok(Some(channel.exit_status().map_err(Exit)?)),
-            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-            Err(err) => return Err(Close(err)),
+        match configs
+            .iter_mut()
+            .find(|(region, _)| *region == target.region)
+        {
+            Some((_, region_config)) => {
+                *region_config = region_config.clone().target(run_target);
+            }
+            None => configs.push((target.region, config.clone().target(run_target))),
         }
     }
+    configs
 }
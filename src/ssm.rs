@@ -0,0 +1,767 @@
+//! Runs commands on instances through AWS Systems Manager rather than SSH, see
+//! [`TransportKind::Ssm`](crate::TransportKind::Ssm).
+//!
+//! Files and command output are staged in S3 when a bucket is given, see
+//! [`RunConfig::ssm_bucket`](crate::RunConfig::ssm_bucket), else files are copied in chunks
+//! through the commands themselves.
+
+use crate::{
+    kill_script, pid_script, shell_quote, write_prefixed, ExecError, MainError, SdkError,
+    Transport, KILL_TIMEOUT,
+};
+use aws_sdk_s3::operation::{
+    delete_object::DeleteObjectError, get_object::GetObjectError, put_object::PutObjectError,
+};
+use aws_sdk_s3::presigning::{PresigningConfig, PresigningConfigError};
+use aws_sdk_ssm::error::ProvideErrorMetadata;
+use aws_sdk_ssm::operation::{
+    cancel_command::CancelCommandError,
+    describe_instance_information::DescribeInstanceInformationError,
+    get_command_invocation::{GetCommandInvocationError, GetCommandInvocationOutput},
+    send_command::SendCommandError,
+};
+use aws_sdk_ssm::types::{CommandInvocationStatus, InstanceInformationStringFilter, PingStatus};
+use std::io::Write;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// The document which runs a shell script on Linux instances.
+const RUN_SHELL_SCRIPT_DOCUMENT: &str = "AWS-RunShellScript";
+
+/// The initial time to wait between polls of a command for its result.
+const COMMAND_POLL_INITIAL_SLEEP: Duration = Duration::from_millis(500);
+
+/// The maximum time to wait between polls of a command for its result.
+const COMMAND_POLL_MAX_SLEEP: Duration = Duration::from_secs(5);
+
+/// The time to wait between polls of the agent on a newly started instance.
+const AGENT_POLL_SLEEP: Duration = Duration::from_secs(5);
+
+/// How much longer than the timeout of a command the agent is told to let it run, so the command
+/// is killed with its process group on timeout rather than by the agent.
+const EXECUTION_TIMEOUT_MARGIN: Duration = Duration::from_mins(1);
+
+/// The maximum execution timeout `AWS-RunShellScript` accepts.
+const MAX_EXECUTION_TIMEOUT: Duration = Duration::from_hours(48);
+
+/// The number of characters of stdout `GetCommandInvocation` returns, the rest is truncated.
+const STDOUT_LIMIT: usize = 24_000;
+
+/// The number of characters of stderr `GetCommandInvocation` returns, the rest is truncated.
+const STDERR_LIMIT: usize = 8_000;
+
+/// The number of bytes of a file written or read by each command.
+///
+/// The base64 encoding of this is kept within the limit on the size of a command and on the
+/// stdout returned by `GetCommandInvocation`.
+const FILE_CHUNK_SIZE: usize = 16 * 1024;
+
+/// The size beyond which copying a file in chunks is slow enough to warn about.
+const SLOW_CHUNKED_SIZE: usize = 64 * FILE_CHUNK_SIZE;
+
+/// The longest a presigned URL can be valid for.
+const MAX_PRESIGNED_EXPIRY: Duration = Duration::from_hours(24 * 7);
+
+/// The directory the agent uploads the output of `AWS-RunShellScript` to, under the key prefix,
+/// command id and instance id.
+const RUN_SHELL_SCRIPT_OUTPUT_DIR: &str = "awsrunShellScript/0.awsrunShellScript";
+
+/// Errors from the SSM API.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("Failed to describe instance information: {0}")]
+    DescribeInstanceInformation(SdkError<DescribeInstanceInformationError>),
+    #[error("Failed to send command: {0}")]
+    SendCommand(SdkError<SendCommandError>),
+    #[error("Failed to get command invocation: {0}")]
+    GetCommandInvocation(SdkError<GetCommandInvocationError>),
+    #[error("Failed to cancel command: {0}")]
+    CancelCommand(SdkError<CancelCommandError>),
+    #[error("Sent command missing id.")]
+    CommandId,
+    #[error("Failed to read file: {0}")]
+    ReadFile(String),
+    #[error("Failed to put object: {0}")]
+    PutObject(SdkError<PutObjectError>),
+    #[error("Failed to get object: {0}")]
+    GetObject(SdkError<GetObjectError>),
+    #[error("Failed to read object: {0}")]
+    ReadObject(aws_smithy_http::byte_stream::error::Error),
+    #[error("Failed to delete object: {0}")]
+    DeleteObject(SdkError<DeleteObjectError>),
+    #[error("Invalid presigned URL expiry: {0}")]
+    Presign(PresigningConfigError),
+}
+
+/// An S3 bucket files and command output are staged in.
+#[derive(Debug, Clone)]
+pub(crate) struct Bucket {
+    pub(crate) client: aws_sdk_s3::Client,
+    pub(crate) name: String,
+    /// The prefix of the key of every object, without a trailing `/`.
+    pub(crate) prefix: String,
+}
+
+impl Bucket {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+        debug!("Putting s3://{}/{key}", self.name);
+        self.client
+            .put_object()
+            .bucket(&self.name)
+            .key(key)
+            .body(data.to_vec().into())
+            .send()
+            .await
+            .map_err(Error::PutObject)?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        debug!("Getting s3://{}/{key}", self.name);
+        let response = self
+            .client
+            .get_object()
+            .bucket(&self.name)
+            .key(key)
+            .send()
+            .await
+            .map_err(Error::GetObject)?;
+        let data = response.body.collect().await.map_err(Error::ReadObject)?;
+        Ok(data.into_bytes().to_vec())
+    }
+
+    /// Deletes the object at `key`, logging rather than returning failure as it only leaves the
+    /// object behind.
+    async fn delete(&self, key: &str) {
+        debug!("Deleting s3://{}/{key}", self.name);
+        if let Err(err) = self
+            .client
+            .delete_object()
+            .bucket(&self.name)
+            .key(key)
+            .send()
+            .await
+        {
+            error!(
+                "Failed to delete s3://{}/{key}: {}",
+                self.name,
+                Error::DeleteObject(err)
+            );
+        }
+    }
+
+    /// A URL to download the object at `key` from, valid for `expires_in`.
+    async fn presigned_get(&self, key: &str, expires_in: Duration) -> Result<String, Error> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.name)
+            .key(key)
+            .presigned(presigning_config(expires_in)?)
+            .await
+            .map_err(Error::GetObject)?;
+        Ok(request.uri().to_string())
+    }
+
+    /// A URL to upload the object at `key` to, valid for `expires_in`.
+    async fn presigned_put(&self, key: &str, expires_in: Duration) -> Result<String, Error> {
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.name)
+            .key(key)
+            .presigned(presigning_config(expires_in)?)
+            .await
+            .map_err(Error::PutObject)?;
+        Ok(request.uri().to_string())
+    }
+}
+
+/// Presigned URLs valid for `expires_in`, within the limits S3 allows.
+fn presigning_config(expires_in: Duration) -> Result<PresigningConfig, Error> {
+    PresigningConfig::expires_in(expires_in.clamp(Duration::from_secs(1), MAX_PRESIGNED_EXPIRY))
+        .map_err(Error::Presign)
+}
+
+/// Whether the command of `invocation` has finished, successfully or not.
+fn finished(invocation: &GetCommandInvocationOutput) -> bool {
+    !matches!(
+        invocation.status(),
+        None | Some(
+            CommandInvocationStatus::Pending
+                | CommandInvocationStatus::InProgress
+                | CommandInvocationStatus::Delayed
+                | CommandInvocationStatus::Cancelling
+        )
+    )
+}
+
+/// The ping status of the agent on the instance, `None` until it first registers.
+async fn ping_status(
+    client: &aws_sdk_ssm::Client,
+    instance_id: &str,
+) -> Result<Option<PingStatus>, Error> {
+    let response = client
+        .describe_instance_information()
+        .filters(
+            InstanceInformationStringFilter::builder()
+                .key("InstanceIds")
+                .values(instance_id)
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(Error::DescribeInstanceInformation)?;
+    Ok(response
+        .instance_information_list()
+        .and_then(|list| list.first())
+        .and_then(|information| information.ping_status())
+        .cloned())
+}
+
+/// A [`Transport`] running commands as root on an instance through the SSM agent.
+pub(crate) struct Session {
+    client: aws_sdk_ssm::Client,
+    bucket: Option<Bucket>,
+    instance_id: String,
+}
+
+impl Session {
+    /// Waits until the agent on the instance is online.
+    pub(crate) async fn connect(
+        client: &aws_sdk_ssm::Client,
+        bucket: Option<Bucket>,
+        instance_id: &str,
+        timeout: &Duration,
+    ) -> Result<Self, MainError> {
+        info!("Waiting for SSM agent to be online");
+        let wait = async {
+            loop {
+                match ping_status(client, instance_id).await {
+                    Ok(Some(PingStatus::Online)) => return Ok(()),
+                    Ok(status) => debug!("SSM agent not online: {status:?}"),
+                    // The instance isn't known to SSM until its agent registers.
+                    Err(Error::DescribeInstanceInformation(err))
+                        if err.code() == Some("InvalidInstanceId") => {}
+                    Err(err) => return Err(MainError::Ssm(err)),
+                }
+                tokio::time::sleep(AGENT_POLL_SLEEP).await;
+            }
+        };
+        tokio::time::timeout(*timeout, wait)
+            .await
+            .map_err(|_| MainError::SsmAgentTimeout(String::from(instance_id)))??;
+        Ok(Self {
+            client: client.clone(),
+            bucket,
+            instance_id: String::from(instance_id),
+        })
+    }
+
+    /// The key of a new object for this instance in the bucket.
+    fn object_key(bucket: &Bucket, instance_id: &str) -> String {
+        format!("{}/{instance_id}/{}", bucket.prefix, uuid::Uuid::new_v4())
+    }
+
+    /// The prefix the agent uploads command output under, see [`output_key`].
+    fn output_prefix(bucket: &Bucket, instance_id: &str) -> String {
+        format!("{}/{instance_id}/output", bucket.prefix)
+    }
+
+    /// Runs `script`, returning `None` if it didn't finish within `timeout`, in which case the
+    /// command is cancelled.
+    ///
+    /// If `upload_output` the agent uploads its full output to the bucket, if there is one, see
+    /// [`Session::output`].
+    async fn run(
+        &self,
+        script: &str,
+        timeout: &Duration,
+        upload_output: bool,
+    ) -> Result<Option<GetCommandInvocationOutput>, Error> {
+        let start = Instant::now();
+        let execution_timeout = (*timeout + EXECUTION_TIMEOUT_MARGIN).min(MAX_EXECUTION_TIMEOUT);
+        debug!("Sending command: {script:?}");
+        let response = self
+            .client
+            .send_command()
+            .instance_ids(&self.instance_id)
+            .document_name(RUN_SHELL_SCRIPT_DOCUMENT)
+            .parameters("commands", vec![String::from(script)])
+            .parameters(
+                "executionTimeout",
+                vec![execution_timeout.as_secs().to_string()],
+            )
+            .set_output_s3_bucket_name(
+                self.bucket
+                    .as_ref()
+                    .filter(|_| upload_output)
+                    .map(|bucket| bucket.name.clone()),
+            )
+            .set_output_s3_key_prefix(
+                self.bucket
+                    .as_ref()
+                    .filter(|_| upload_output)
+                    .map(|bucket| Self::output_prefix(bucket, &self.instance_id)),
+            )
+            .send()
+            .await
+            .map_err(Error::SendCommand)?;
+        let command_id = response
+            .command()
+            .and_then(|command| command.command_id())
+            .ok_or(Error::CommandId)?;
+        let mut sleep = COMMAND_POLL_INITIAL_SLEEP;
+        loop {
+            tokio::time::sleep(sleep.min(timeout.saturating_sub(start.elapsed()))).await;
+            match self
+                .client
+                .get_command_invocation()
+                .command_id(command_id)
+                .instance_id(&self.instance_id)
+                .send()
+                .await
+            {
+                Ok(invocation) if finished(&invocation) => return Ok(Some(invocation)),
+                Ok(_) => {}
+                // The invocation isn't visible immediately after the command is sent.
+                Err(err) if err.code() == Some("InvocationDoesNotExist") => {}
+                Err(err) => return Err(Error::GetCommandInvocation(err)),
+            }
+            if start.elapsed() >= *timeout {
+                if let Err(err) = self
+                    .client
+                    .cancel_command()
+                    .command_id(command_id)
+                    .instance_ids(&self.instance_id)
+                    .send()
+                    .await
+                {
+                    error!(
+                        "Failed to cancel command {command_id}: {}",
+                        Error::CancelCommand(err)
+                    );
+                }
+                return Ok(None);
+            }
+            sleep = (sleep * 2).min(COMMAND_POLL_MAX_SLEEP);
+        }
+    }
+
+    /// Runs a step of writing a file, within what remains of `timeout` since `start`.
+    async fn write_script(
+        &self,
+        script: &str,
+        timeout: &Duration,
+        start: &Instant,
+    ) -> Result<(), MainError> {
+        let remaining = timeout.saturating_sub(start.elapsed());
+        match self
+            .run(script, &remaining, false)
+            .await
+            .map_err(MainError::Ssm)?
+        {
+            Some(invocation) if invocation.response_code() == 0 => Ok(()),
+            Some(invocation) => Err(MainError::SsmWriteFailed(
+                invocation.response_code(),
+                String::from(invocation.standard_error_content().unwrap_or_default()),
+            )),
+            None => Err(MainError::TransferTimeout),
+        }
+    }
+
+    /// Reads the file at `path` on the instance, through the bucket if there is one, else
+    /// `FILE_CHUNK_SIZE` bytes per command.
+    ///
+    /// Returns `None` if it isn't read within `timeout`.
+    async fn read_file(&self, path: &str, timeout: &Duration) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(bucket) = &self.bucket {
+            return self.read_file_through(bucket, path, timeout).await;
+        }
+        let start = Instant::now();
+        let mut data = Vec::new();
+        loop {
+            let script = read_chunk_script(path, data.len());
+            let remaining = timeout.saturating_sub(start.elapsed());
+            let Some(invocation) = self.run(&script, &remaining, false).await? else {
+                return Ok(None);
+            };
+            if invocation.response_code() != 0 {
+                return Err(Error::ReadFile(String::from(
+                    invocation.standard_error_content().unwrap_or_default(),
+                )));
+            }
+            let chunk = aws_smithy_types::base64::decode(
+                invocation
+                    .standard_output_content()
+                    .unwrap_or_default()
+                    .trim(),
+            )
+            .map_err(|err| Error::ReadFile(err.to_string()))?;
+            data.extend_from_slice(&chunk);
+            if chunk.len() < FILE_CHUNK_SIZE {
+                if data.len() > SLOW_CHUNKED_SIZE {
+                    warn!(
+                        "Read {} bytes in {} commands, set an SSM bucket to transfer through S3",
+                        data.len(),
+                        data.len().div_ceil(FILE_CHUNK_SIZE)
+                    );
+                }
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    /// Reads the file at `path` on the instance by uploading it to `bucket`.
+    async fn read_file_through(
+        &self,
+        bucket: &Bucket,
+        path: &str,
+        timeout: &Duration,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let start = Instant::now();
+        let key = Self::object_key(bucket, &self.instance_id);
+        let url = bucket.presigned_put(&key, *timeout).await?;
+        let remaining = timeout.saturating_sub(start.elapsed());
+        let result = match self
+            .run(&upload_script(path, &url), &remaining, false)
+            .await
+        {
+            Ok(Some(invocation)) if invocation.response_code() == 0 => {
+                bucket.get(&key).await.map(Some)
+            }
+            Ok(Some(invocation)) => Err(Error::ReadFile(String::from(
+                invocation.standard_error_content().unwrap_or_default(),
+            ))),
+            Ok(None) => Ok(None),
+            Err(err) => Err(err),
+        };
+        bucket.delete(&key).await;
+        result
+    }
+
+    /// Writes `data` to `remote_path` on the instance by downloading it from `bucket`.
+    async fn write_file_through(
+        &self,
+        bucket: &Bucket,
+        data: &[u8],
+        remote_path: &str,
+        timeout: &Duration,
+    ) -> Result<(), MainError> {
+        let start = Instant::now();
+        let key = Self::object_key(bucket, &self.instance_id);
+        info!(
+            "Writing {} bytes through s3://{}/{key}",
+            data.len(),
+            bucket.name
+        );
+        bucket.put(&key, data).await.map_err(MainError::Ssm)?;
+        let result = match bucket.presigned_get(&key, *timeout).await {
+            Ok(url) => {
+                self.write_script(&download_script(&url, remote_path), timeout, &start)
+                    .await
+            }
+            Err(err) => Err(MainError::Ssm(err)),
+        };
+        bucket.delete(&key).await;
+        result
+    }
+
+    /// The stdout and stderr of a finished command.
+    ///
+    /// `GetCommandInvocation` truncates them, so when they may have been truncated they are read
+    /// from the bucket if the agent uploaded them there, else a warning is logged.
+    async fn output(&self, invocation: &GetCommandInvocationOutput) -> (String, String) {
+        let outputs = [
+            ("stdout", invocation.standard_output_content(), STDOUT_LIMIT),
+            ("stderr", invocation.standard_error_content(), STDERR_LIMIT),
+        ];
+        let mut contents = Vec::with_capacity(outputs.len());
+        for (name, content, limit) in outputs {
+            let content = content.unwrap_or_default();
+            if !truncated(content, limit) {
+                contents.push(String::from(content));
+                continue;
+            }
+            let Some(bucket) = &self.bucket else {
+                warn!(
+                    "The {name} of the command was truncated to {limit} characters, set an SSM \
+                    bucket to get all of it"
+                );
+                contents.push(String::from(content));
+                continue;
+            };
+            let key = output_key(
+                &Self::output_prefix(bucket, &self.instance_id),
+                invocation.command_id().unwrap_or_default(),
+                &self.instance_id,
+                name,
+            );
+            match bucket.get(&key).await {
+                Ok(full) => contents.push(String::from_utf8_lossy(&full).into_owned()),
+                Err(err) => {
+                    warn!(
+                        "The {name} of the command was truncated to {limit} characters and \
+                        reading all of it from s3://{}/{key} failed: {err}",
+                        bucket.name
+                    );
+                    contents.push(String::from(content));
+                }
+            }
+        }
+        if let Some(bucket) = &self.bucket {
+            for name in ["stdout", "stderr"] {
+                let key = output_key(
+                    &Self::output_prefix(bucket, &self.instance_id),
+                    invocation.command_id().unwrap_or_default(),
+                    &self.instance_id,
+                    name,
+                );
+                bucket.delete(&key).await;
+            }
+        }
+        let stderr = contents.pop().unwrap_or_default();
+        let stdout = contents.pop().unwrap_or_default();
+        (stdout, stderr)
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for Session {
+    async fn exec(
+        &self,
+        command: &str,
+        timeout: &Duration,
+        prefix: &str,
+        capture_stdout: bool,
+    ) -> Result<(Option<i32>, Vec<u8>), ExecError> {
+        let id = uuid::Uuid::new_v4();
+        let pid_path = format!("/tmp/aws-ec2-{id}.pid");
+        let stdout_path = format!("/tmp/aws-ec2-{id}.out");
+        // The agent runs scripts in its own directory, so the command is run in the home
+        // directory as over SSH, and in a new session so it has a process group to kill.
+        let script = pid_script(
+            &pid_path,
+            &format!("cd \"${{HOME:-/root}}\" || exit 1\n{command}"),
+        );
+        let redirect = if capture_stdout {
+            format!(" > {}", shell_quote(&stdout_path))
+        } else {
+            String::new()
+        };
+        let script = format!("setsid -w sh -c {}{redirect}", shell_quote(&script));
+
+        let start = Instant::now();
+        info!("Running command: {command:?}");
+        let Some(invocation) = self
+            .run(&script, timeout, true)
+            .await
+            .map_err(|err| ExecError::Ssm(Box::new(err)))?
+        else {
+            error!("Command timed out after {timeout:?}, killing it");
+            let kill = format!(
+                "{}; rm -f {}",
+                kill_script(&pid_path),
+                shell_quote(&stdout_path)
+            );
+            match self.run(&kill, &KILL_TIMEOUT, false).await {
+                Ok(Some(invocation)) if invocation.response_code() == 0 => {}
+                Ok(invocation) => error!(
+                    "Failed to kill command: {:?}",
+                    invocation.map(|invocation| invocation.response_code())
+                ),
+                Err(err) => error!("Failed to kill command: {err}"),
+            }
+            return Ok((None, Vec::new()));
+        };
+
+        // The agent only returns the output once the command finishes.
+        let (stdout, stderr) = self.output(&invocation).await;
+        write_output(&mut std::io::stdout(), prefix, &stdout);
+        write_output(&mut std::io::stderr(), prefix, &stderr);
+
+        let output = if capture_stdout {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            let read = self.read_file(&stdout_path, &remaining).await;
+            if let Err(err) = self
+                .run(
+                    &format!("rm -f {}", shell_quote(&stdout_path)),
+                    &KILL_TIMEOUT,
+                    false,
+                )
+                .await
+            {
+                error!("Failed to remove {stdout_path}: {err}");
+            }
+            match read.map_err(|err| ExecError::Ssm(Box::new(err)))? {
+                Some(output) => output,
+                None => return Ok((None, Vec::new())),
+            }
+        } else {
+            Vec::new()
+        };
+        Ok((Some(invocation.response_code()), output))
+    }
+
+    async fn write_file(
+        &self,
+        data: &[u8],
+        remote_path: &str,
+        timeout: &Duration,
+    ) -> Result<(), MainError> {
+        // Files fitting in one command aren't worth the round trips through the bucket.
+        if let Some(bucket) = self
+            .bucket
+            .as_ref()
+            .filter(|_| data.len() > FILE_CHUNK_SIZE)
+        {
+            return self
+                .write_file_through(bucket, data, remote_path, timeout)
+                .await;
+        }
+        let start = Instant::now();
+        let commands = data.len().div_ceil(FILE_CHUNK_SIZE) + 1;
+        if data.len() > SLOW_CHUNKED_SIZE {
+            warn!(
+                "Writing {} bytes in {commands} commands, set an SSM bucket to transfer through S3",
+                data.len()
+            );
+        } else {
+            info!("Writing {} bytes in {commands} commands", data.len());
+        }
+        for (i, chunk) in data.chunks(FILE_CHUNK_SIZE).enumerate() {
+            let script = write_chunk_script(chunk, remote_path, i == 0);
+            self.write_script(&script, timeout, &start).await?;
+        }
+        self.write_script(&decode_script(remote_path), timeout, &start)
+            .await
+    }
+}
+
+/// Whether `content` returned by `GetCommandInvocation` may have been truncated at `limit`
+/// characters.
+fn truncated(content: &str, limit: usize) -> bool {
+    content.chars().count() >= limit
+}
+
+/// The key the agent uploads the `name` output (`stdout` or `stderr`) of a command to.
+fn output_key(prefix: &str, command_id: &str, instance_id: &str, name: &str) -> String {
+    format!("{prefix}/{command_id}/{instance_id}/{RUN_SHELL_SCRIPT_OUTPUT_DIR}/{name}")
+}
+
+/// The script downloading `url` to `path` with `curl`, or `wget` where it isn't installed.
+fn download_script(url: &str, path: &str) -> String {
+    let (url, path) = (shell_quote(url), shell_quote(path));
+    format!(
+        "if command -v curl >/dev/null 2>&1; then curl -fsS -o {path} {url}; \
+        else wget -q -O {path} {url}; fi"
+    )
+}
+
+/// The script uploading the file at `path` to `url` with `curl`, or `wget` where it isn't
+/// installed.
+fn upload_script(path: &str, url: &str) -> String {
+    let (path, url) = (shell_quote(path), shell_quote(url));
+    format!(
+        "if command -v curl >/dev/null 2>&1; then curl -fsS -T {path} {url}; \
+        else wget -q -O /dev/null --method=PUT --body-file={path} {url}; fi"
+    )
+}
+
+/// The script printing the base64 encoding of at most `FILE_CHUNK_SIZE` bytes of the file at
+/// `path` from `offset`.
+fn read_chunk_script(path: &str, offset: usize) -> String {
+    format!(
+        "tail -c +{} {} | head -c {FILE_CHUNK_SIZE} | base64 -w 0",
+        offset + 1,
+        shell_quote(path)
+    )
+}
+
+/// The script appending the base64 encoding of `chunk` to `<path>.base64`, truncating anything
+/// left there if it is the `first` chunk.
+fn write_chunk_script(chunk: &[u8], path: &str, first: bool) -> String {
+    let encoded = aws_smithy_types::base64::encode(chunk);
+    let redirect = if first { ">" } else { ">>" };
+    format!(
+        "printf '%s' '{encoded}' {redirect} {}",
+        shell_quote(&format!("{path}.base64"))
+    )
+}
+
+/// The script decoding the chunks written to `<path>.base64` into `path`.
+fn decode_script(path: &str) -> String {
+    let encoded_path = shell_quote(&format!("{path}.base64"));
+    format!(
+        "touch {encoded_path} && base64 -d {encoded_path} > {}; \
+        code=$?; rm -f {encoded_path}; exit $code",
+        shell_quote(path)
+    )
+}
+
+/// Writes `content` with each line prefixed by `prefix`, ending it with a newline if it doesn't.
+fn write_output(out: &mut impl Write, prefix: &str, content: &str) {
+    let mut line = Vec::new();
+    write_prefixed(out, prefix, &mut line, content.as_bytes()).unwrap();
+    if !line.is_empty() {
+        write_prefixed(out, prefix, &mut line, b"\n").unwrap();
+    }
+    out.flush().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_scripts_quote_paths() {
+        let path = "/tmp/my dir/a'b;$(touch x).tar";
+        let quoted = "'/tmp/my dir/a'\\''b;$(touch x).tar";
+        assert_eq!(
+            read_chunk_script(path, 16384),
+            format!("tail -c +16385 {quoted}' | head -c 16384 | base64 -w 0")
+        );
+        assert_eq!(
+            write_chunk_script(b"hi", path, true),
+            format!("printf '%s' 'aGk=' > {quoted}.base64'")
+        );
+        assert!(write_chunk_script(b"hi", path, false).contains(" >> "));
+        assert_eq!(
+            decode_script(path),
+            format!(
+                "touch {quoted}.base64' && base64 -d {quoted}.base64' > {quoted}'; \
+                code=$?; rm -f {quoted}.base64'; exit $code"
+            )
+        );
+    }
+
+    #[test]
+    fn s3_transfer_scripts() {
+        let url = "https://bucket.s3.eu-west-2.amazonaws.com/aws-ec2/i-0/1?X-Amz-Signature=a&b";
+        assert_eq!(
+            download_script(url, "/tmp/a b.tar"),
+            format!(
+                "if command -v curl >/dev/null 2>&1; then curl -fsS -o '/tmp/a b.tar' '{url}'; \
+                else wget -q -O '/tmp/a b.tar' '{url}'; fi"
+            )
+        );
+        assert_eq!(
+            upload_script("/tmp/a b.out", url),
+            format!(
+                "if command -v curl >/dev/null 2>&1; then curl -fsS -T '/tmp/a b.out' '{url}'; \
+                else wget -q -O /dev/null --method=PUT --body-file='/tmp/a b.out' '{url}'; fi"
+            )
+        );
+    }
+
+    #[test]
+    fn command_output() {
+        assert!(!truncated(&"é".repeat(STDERR_LIMIT - 1), STDERR_LIMIT));
+        assert!(truncated(&"é".repeat(STDERR_LIMIT), STDERR_LIMIT));
+        assert_eq!(
+            output_key("aws-ec2/run/i-0/output", "c-1", "i-0", "stdout"),
+            "aws-ec2/run/i-0/output/c-1/i-0/awsrunShellScript/0.awsrunShellScript/stdout"
+        );
+    }
+}